@@ -0,0 +1,30 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use muflac::bitstream::BufferedBitstreamReader;
+use muflac::decoder::{Decoder, Recovery};
+use muflac::push_decoder::{PushDecoder, PushEvent};
+
+fuzz_target!(|data: &[u8]| {
+    for recovery in [Recovery::Off, Recovery::Skip, Recovery::Silence].iter() {
+        let mut decoder = match Decoder::new(BufferedBitstreamReader::new(data)) {
+            Ok(decoder) => decoder,
+            Err(_) => break,
+        };
+        decoder.set_recovery(*recovery);
+        while let Ok(Some(_)) = decoder.next_frame() {}
+    }
+
+    // Cut into uneven chunks, so parsing restarts at many places
+    let mut decoder = PushDecoder::new();
+    let mut chunks = data.chunks(4099);
+    loop {
+        match decoder.next_event() {
+            Ok(PushEvent::NeedMoreData) => match chunks.next() {
+                Some(chunk) => decoder.feed(chunk),
+                None => decoder.finish(),
+            },
+            Ok(PushEvent::End) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+});
@@ -0,0 +1,42 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use muflac::bitstream::{BitstreamReader, BufferedBitstreamReader};
+use muflac::block_parser::{read_magic, read_metadata_blocks};
+use muflac::frame_parser::read_frame;
+use muflac::metadata_types::MetadataBlockStreamInfo;
+use muflac::sample_decoder::decode_frame;
+
+// Used for input that is only frames, without a FLAC header
+const STREAM_INFO: MetadataBlockStreamInfo = MetadataBlockStreamInfo {
+    min_block_size: 4096,
+    max_block_size: 4096,
+    min_frame_size: 0,
+    max_frame_size: 0,
+    sample_rate: 44100,
+    num_channels: 2,
+    sample_depth: 16,
+    num_samples: 0,
+    decoded_checksum: 0,
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut reader = BufferedBitstreamReader::new(data);
+    let stream_info = if data.starts_with(b"fLaC") {
+        let _ = read_magic(&mut reader);
+        match read_metadata_blocks(&mut reader) {
+            Ok((stream_info, _)) => stream_info,
+            Err(_) => return,
+        }
+    } else {
+        STREAM_INFO
+    };
+
+    while !matches!(reader.is_at_end(), Ok(true) | Err(_)) {
+        match read_frame(&mut reader, &stream_info) {
+            Ok(frame) => {
+                decode_frame(&frame);
+            }
+            Err(_) => return,
+        }
+    }
+});
@@ -0,0 +1,120 @@
+#![no_main]
+//! Mutates the subframes of one frame of a valid stream and fixes up its CRC-16 afterwards, so
+//! the mutations reach the subframe and residual parsers instead of failing the checksum
+
+use libfuzzer_sys::{fuzz_mutator, fuzz_target, fuzzer_mutate};
+use muflac::bitstream::BufferedBitstreamReader;
+use muflac::decoder::Decoder;
+use muflac::diagnostics::Event;
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = match Decoder::new(BufferedBitstreamReader::new(data)) {
+        Ok(decoder) => decoder,
+        Err(_) => return,
+    };
+    while let Ok(Some(_)) = decoder.next_frame() {}
+});
+
+fuzz_mutator!(|data: &mut [u8], size: usize, max_size: usize, seed: u32| {
+    let frames = find_frames(&data[..size]);
+    if frames.is_empty() {
+        return fuzzer_mutate(data, size, max_size);
+    }
+    let frame = frames[seed as usize % frames.len()];
+
+    let mut body = data[frame.body..frame.end - 2].to_vec();
+    let length = body.len();
+    let max_length = max_size.saturating_sub(size - length);
+    body.resize(max_length, 0);
+    let length = fuzzer_mutate(&mut body, length, max_length);
+    body.truncate(length);
+
+    let mut mutated = data[..frame.body].to_vec();
+    mutated.extend_from_slice(&body);
+    let crc = crc16(&mutated[frame.start..]);
+    mutated.extend_from_slice(&crc.to_be_bytes());
+    mutated.extend_from_slice(&data[frame.end..size]);
+
+    let size = mutated.len().min(max_size);
+    data[..size].copy_from_slice(&mutated[..size]);
+    size
+});
+
+#[derive(Debug, Copy, Clone)]
+struct FrameBytes {
+    start: usize,
+    body: usize, // Start of the first subframe
+    end: usize,  // Including the CRC-16
+}
+
+/// Locates the frames that decode without errors, using the positions the parser reports
+fn find_frames(data: &[u8]) -> Vec<FrameBytes> {
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut reader = BufferedBitstreamReader::new(data);
+    let hook_events = events.clone();
+    reader.set_diagnostics(move |event: &Event| match event {
+        Event::FrameHeader { position, .. } => hook_events.borrow_mut().push((position.byte, 0)),
+        Event::Subframe {
+            position,
+            channel: 0,
+            ..
+        } => {
+            if let Some(frame) = hook_events.borrow_mut().last_mut() {
+                frame.1 = position.byte;
+            }
+        }
+        _ => {}
+    });
+
+    let mut decoder = match Decoder::new(reader) {
+        Ok(decoder) => decoder,
+        Err(_) => return Vec::new(),
+    };
+    let mut num_frames = 0;
+    let mut end = data.len();
+    loop {
+        match decoder.next_frame() {
+            Ok(Some(_)) => num_frames += 1,
+            Ok(None) => break,
+            Err(_) => {
+                // The damaged frame ends the last complete one, unless its header is damaged too
+                match events.borrow().get(num_frames) {
+                    Some(&(start, _)) => end = start,
+                    None => num_frames = num_frames.saturating_sub(1),
+                }
+                break;
+            }
+        }
+    }
+
+    if num_frames == 0 {
+        return Vec::new();
+    }
+    let events = events.borrow();
+    let mut frames: Vec<FrameBytes> = events[..num_frames]
+        .iter()
+        .zip(events[1..].iter().map(|e| e.0).chain(Some(end)))
+        .map(|(&(start, body), end)| FrameBytes { start, body, end })
+        .collect();
+    // Drops frames without a subframe event and frames too short to hold their CRC-16
+    frames.retain(|frame| frame.body > frame.start && frame.end >= frame.body + 2);
+    frames
+}
+
+/// CRC-16 of FLAC frames, polynomial 0x8005
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for byte in bytes {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x8005
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use muflac::limits::Limits;
+use muflac::matroska_parser::MatroskaFlacReader;
+
+// Small enough that a lying element size cannot exhaust the fuzzer's memory
+const LIMITS: Limits = Limits {
+    max_item_allocation: 1 << 20,
+    ..Limits::DEFAULT
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut mkv = match MatroskaFlacReader::with_limits(data, LIMITS) {
+        Ok(mkv) => mkv,
+        Err(_) => return,
+    };
+    while let Ok(Some(_)) = mkv.next_frame() {}
+});
@@ -0,0 +1,21 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use muflac::bitstream::BufferedBitstreamReader;
+use muflac::block_parser::{read_magic, read_metadata_block, read_metadata_blocks};
+
+fuzz_target!(|data: &[u8]| {
+    // Block by block, without the checks on the order of the blocks
+    let mut reader = BufferedBitstreamReader::new(data);
+    if read_magic(&mut reader).is_ok() {
+        while let Ok(block) = read_metadata_block(&mut reader) {
+            if block.is_last {
+                break;
+            }
+        }
+    }
+
+    let mut reader = BufferedBitstreamReader::new(data);
+    if read_magic(&mut reader).is_ok() {
+        let _ = read_metadata_blocks(&mut reader);
+    }
+});
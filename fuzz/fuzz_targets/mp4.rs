@@ -0,0 +1,28 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use muflac::limits::Limits;
+use muflac::mp4_parser::Mp4FlacReader;
+use std::io::Cursor;
+
+// Small enough that a lying box or sample table size cannot exhaust the fuzzer's memory
+const LIMITS: Limits = Limits {
+    max_item_allocation: 1 << 20,
+    ..Limits::DEFAULT
+};
+
+fuzz_target!(|data: &[u8]| {
+    let mut mp4 = match Mp4FlacReader::with_limits(Cursor::new(data), LIMITS) {
+        Ok(mp4) => mp4,
+        Err(_) => return,
+    };
+    let num_samples: Vec<usize> = mp4
+        .tracks()
+        .iter()
+        .map(|track| track.samples.len())
+        .collect();
+    for (track, &num_samples) in num_samples.iter().enumerate() {
+        for sample in 0..num_samples {
+            let _ = mp4.read_sample(track, sample);
+        }
+    }
+});
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Seeds the corpus of every fuzz target with the files in test_flac, which are stored with Git LFS
+set -e
+cd "$(dirname "$0")"
+for target in metadata frame decoder frame_mutation matroska mp4; do
+    mkdir -p "corpus/$target"
+    cp ../test_flac/*.flac ../test_flac/conformance/*.flac "corpus/$target/"
+done
@@ -0,0 +1,186 @@
+use crate::frame_index::FrameIndexEntry;
+use crate::frame_types::{ChannelAssignment, Frame, Residual, SubframeData, SubframeType};
+use alloc::boxed::Box;
+
+/// How the bits of a frame are spent, in the spirit of `flac --analyze`
+#[derive(Debug, Clone)]
+pub struct FrameAnalysis {
+    pub offset: u64,
+    pub size: u32,
+    pub first_sample: u64,
+    pub block_size: u32,
+    pub channel_assignment: ChannelAssignment,
+    pub header_bits: u64, // Including CRC-8
+    pub subframes: Box<[SubframeAnalysis]>,
+    pub footer_bits: u64, // Byte alignment padding and CRC-16
+}
+
+#[derive(Debug, Clone)]
+pub struct SubframeAnalysis {
+    pub subframe_type: Option<SubframeType>,
+    pub sample_depth: u8, // Excluding wasted bits, including the extra bit of a side channel
+    pub wasted_bits: u8,
+    pub lpc_precision: Option<u8>,
+    pub lpc_shift: Option<i8>,
+    pub header_bits: u32,      // Type and wasted bits flag
+    pub warmup_bits: u32,      // Unpredicted samples: warmup, the CONSTANT value or all of VERBATIM
+    pub coefficient_bits: u32, // LPC precision, shift and coefficients
+    pub residual_bits: u64,    // Including coding method and partition order
+    pub partitions: Box<[PartitionAnalysis]>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionAnalysis {
+    pub rice_parameter: u8,
+    pub escape_bits: Option<u8>, // Bits per sample of an escaped partition
+    pub num_samples: u32,
+    pub bits: u64, // Including the parameter
+}
+
+impl SubframeAnalysis {
+    pub fn total_bits(&self) -> u64 {
+        (self.header_bits + self.warmup_bits + self.coefficient_bits) as u64 + self.residual_bits
+    }
+}
+
+/// Accounts for every bit of a frame found by a `FrameIndex`
+pub fn analyze_frame(entry: &FrameIndexEntry, frame: &Frame) -> FrameAnalysis {
+    let side_channel = frame.channel_assignment.side_channel();
+
+    let subframes: Box<[SubframeAnalysis]> = frame
+        .subframes
+        .iter()
+        .enumerate()
+        .map(|(channel, subframe)| {
+            let depth = frame.sample_depth + (side_channel == Some(channel)) as u8;
+            analyze_subframe(
+                &subframe.data,
+                depth - subframe.wasted_bits,
+                subframe.wasted_bits,
+            )
+        })
+        .collect();
+
+    // The header is a whole number of bytes, anything left after the subframes is padding
+    let total_bits = 8 * entry.size as u64;
+    let subframe_bits: u64 = subframes.iter().map(SubframeAnalysis::total_bits).sum();
+    let header_bits = total_bits.saturating_sub(16 + subframe_bits) / 8 * 8;
+
+    FrameAnalysis {
+        offset: entry.offset,
+        size: entry.size,
+        first_sample: entry.first_sample,
+        block_size: entry.block_size,
+        channel_assignment: entry.channel_assignment,
+        header_bits,
+        footer_bits: total_bits.saturating_sub(header_bits + subframe_bits),
+        subframes,
+    }
+}
+
+fn analyze_subframe(data: &SubframeData, sample_depth: u8, wasted_bits: u8) -> SubframeAnalysis {
+    let depth = sample_depth as u32;
+    let mut analysis = SubframeAnalysis {
+        subframe_type: data.subframe_type(),
+        sample_depth,
+        wasted_bits,
+        lpc_precision: None,
+        lpc_shift: None,
+        // Wasted bits are coded in unary after the flag
+        header_bits: 8 + wasted_bits as u32,
+        warmup_bits: 0,
+        coefficient_bits: 0,
+        residual_bits: 0,
+        partitions: Box::new([]),
+    };
+
+    match data {
+        SubframeData::Constant(_) => analysis.warmup_bits = depth,
+        SubframeData::Verbatim(verbatim) => {
+            analysis.warmup_bits = depth * verbatim.content.len() as u32
+        }
+        SubframeData::Fixed(fixed) => {
+            analysis.warmup_bits = depth * fixed.order as u32;
+            analyze_residual(&fixed.residual, &mut analysis);
+        }
+        SubframeData::LPC(lpc) => {
+            analysis.lpc_precision = Some(lpc.coefficient_precision);
+            analysis.lpc_shift = Some(lpc.shift);
+            analysis.warmup_bits = depth * lpc.order as u32;
+            analysis.coefficient_bits = 4 + 5 + lpc.coefficient_precision as u32 * lpc.order as u32;
+            analyze_residual(&lpc.residual, &mut analysis);
+        }
+        SubframeData::Reserved => {}
+    }
+    analysis
+}
+
+fn analyze_residual(residual: &Residual, analysis: &mut SubframeAnalysis) {
+    let partitions: Box<[PartitionAnalysis]> = residual
+        .partitions
+        .iter()
+        .map(|partition| {
+            let num_samples = partition.residual.len() as u32;
+            let parameter_bits = residual.parameter_size as u64;
+            if let Some(escape_bits) = partition.escape_bits {
+                PartitionAnalysis {
+                    rice_parameter: partition.encoding_parameter,
+                    escape_bits: Some(escape_bits),
+                    num_samples,
+                    bits: parameter_bits + 5 + escape_bits as u64 * num_samples as u64,
+                }
+            } else {
+                // A residual of i32::MIN folds to 2^32 - 1, so count in 64 bits
+                let k = partition.encoding_parameter as u64;
+                let bits: u64 = partition
+                    .residual
+                    .iter()
+                    .map(|value| {
+                        let value = *value as i64;
+                        let folded = ((value << 1) ^ (value >> 63)) as u64;
+                        (folded >> k) + 1 + k
+                    })
+                    .sum();
+                PartitionAnalysis {
+                    rice_parameter: partition.encoding_parameter,
+                    escape_bits: None,
+                    num_samples,
+                    bits: parameter_bits + bits,
+                }
+            }
+        })
+        .collect();
+
+    analysis.residual_bits = 2 + 4 + partitions.iter().map(|p| p.bits).sum::<u64>();
+    analysis.partitions = partitions;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_types::{FixedSubframe, RICEPartition};
+    use alloc::vec;
+
+    #[test]
+    fn counts_extreme_residuals_without_overflow() {
+        let residual = Residual {
+            parameter_size: 4,
+            order: 0,
+            partitions: Box::new([RICEPartition {
+                encoding_parameter: 0,
+                escape_bits: None,
+                residual: vec![i32::MIN, i32::MAX, i32::MIN].into_boxed_slice(),
+            }]),
+        };
+        let data = SubframeData::Fixed(FixedSubframe {
+            order: 0,
+            warmup: Box::new([]),
+            residual,
+        });
+        let analysis = analyze_subframe(&data, 32, 0);
+        // Unary quotient, stop bit and no remainder bits for each sample
+        let bits = 4 + 3 * (1 << 32) - 1;
+        assert_eq!(analysis.partitions[0].bits, bits);
+        assert_eq!(analysis.residual_bits, 6 + bits);
+    }
+}
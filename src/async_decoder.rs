@@ -0,0 +1,100 @@
+use crate::decoder::DecodedFrame;
+use crate::error::Error;
+use crate::limits::Limits;
+use crate::metadata_types::{MetadataBlock, MetadataBlockStreamInfo};
+use crate::push_decoder::PushDecoder;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_core::Stream;
+use futures_io::AsyncRead;
+
+const READ_SIZE: usize = 16 * 1024;
+
+/// Decodes a native FLAC stream from an `AsyncRead`, yielding frames as a `Stream`. The parsing
+/// is shared with `Decoder`, cut off frames are parsed again once more bytes have arrived.
+pub struct AsyncDecoder<R: AsyncRead + Unpin> {
+    reader: R,
+    parser: PushDecoder,
+    read_buffer: Box<[u8]>,
+    finished: bool,
+}
+
+impl<R: AsyncRead + Unpin> AsyncDecoder<R> {
+    /// Reads the metadata of the stream
+    pub async fn new(reader: R) -> Result<Self, Error> {
+        Self::with_limits(reader, Limits::DEFAULT).await
+    }
+
+    pub async fn with_limits(reader: R, limits: Limits) -> Result<Self, Error> {
+        let mut decoder = AsyncDecoder {
+            reader,
+            parser: PushDecoder::new(),
+            read_buffer: vec![0u8; READ_SIZE].into_boxed_slice(),
+            finished: false,
+        };
+        decoder.parser.set_limits(limits);
+
+        while !decoder.parser.read_header()? {
+            poll_fn(|cx| decoder.poll_fill(cx)).await?;
+        }
+        Ok(decoder)
+    }
+
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        self.parser.stream_info().unwrap()
+    }
+
+    /// All metadata blocks, starting with STREAMINFO
+    pub fn metadata(&self) -> &[MetadataBlock] {
+        self.parser.metadata()
+    }
+
+    /// Reads more bytes into the parser, telling it when the input has ended
+    fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        match Pin::new(&mut self.reader).poll_read(cx, &mut self.read_buffer) {
+            Poll::Ready(Ok(0)) => {
+                self.parser.finish();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Ok(length)) => {
+                self.parser.feed(&self.read_buffer[..length]);
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e.into())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Ends after the first error
+impl<R: AsyncRead + Unpin> Stream for AsyncDecoder<R> {
+    type Item = Result<DecodedFrame, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if this.finished {
+                return Poll::Ready(None);
+            }
+
+            match this.parser.next_frame() {
+                Ok(Some(frame)) => return Poll::Ready(Some(Ok(frame))),
+                Ok(None) if this.parser.is_finished() => {
+                    this.finished = true;
+                    return Poll::Ready(None);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    this.finished = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+
+            if let Err(e) = futures_core::ready!(this.poll_fill(cx)) {
+                this.finished = true;
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+    }
+}
@@ -1,6 +1,15 @@
-use crate::error::Error;
+use crate::crc::{crc16, crc8};
+use crate::diagnostics::{Diagnostics, Event};
+use crate::error::{Context, Error, FrameHeaderField, Position};
+use crate::limits::Limits;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::io::{BufReader, Read};
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+#[cfg(feature = "std")]
 use std::path::Path;
 
 pub trait BitstreamReader {
@@ -8,47 +17,160 @@ pub trait BitstreamReader {
     fn read_bytes(&mut self, num_bytes: usize) -> Result<Box<[u8]>, Error>;
     fn read_bits(&mut self, num_bits: usize) -> Result<Box<[bool]>, Error>;
     fn read_unary(&mut self, start: bool) -> Result<u32, Error>;
-    fn read_utf8(&mut self, max_length: isize) -> Result<Box<str>, Error>;
+    fn read_coded_number(&mut self, max_length: u8) -> Result<u64, Error>;
     fn read_unsigned(&mut self, num_bits: u8) -> Result<u128, Error>;
     fn read_signed(&mut self, num_bits: u8) -> Result<i128, Error>;
     fn get_total_position(&self) -> usize;
+    fn is_at_end(&mut self) -> Result<bool, Error>;
+    /// Pushes whole bytes back so they are read again, the reader has to be byte aligned
+    fn unread_bytes(&mut self, bytes: &[u8]);
+    /// Keeps a copy of every byte read from the current position on, which has to be byte
+    /// aligned, so the caller can unread them later
+    fn start_recording(&mut self);
+    /// Stops recording and returns the bytes read since `start_recording`, including the one
+    /// currently being read
+    fn take_recorded(&mut self) -> Vec<u8>;
+    /// Restarts the CRCs of frame data at the current position, which has to be byte aligned
+    fn reset_crc(&mut self);
+    /// CRC-8 of the bytes read since the last reset
+    fn crc8(&self) -> u8;
+    /// CRC-16 of the bytes read since the last reset
+    fn crc16(&self) -> u16;
+    /// Passes a parser event to the diagnostics hook, if there is one
+    fn report(&mut self, _event: &Event) {}
+    /// What the parsers reading from this reader accept
+    fn limits(&self) -> Limits {
+        Limits::DEFAULT
+    }
+
+    /// Reads and discards `num_bytes` bytes without holding them in memory all at once
+    fn skip_bytes(&mut self, num_bytes: usize) -> Result<(), Error> {
+        let mut remaining = num_bytes;
+        while remaining > 0 {
+            let chunk = remaining.min(4096);
+            self.read_bytes(chunk)?;
+            remaining -= chunk;
+        }
+        Ok(())
+    }
+}
+
+/// Where a `BufferedBitstreamReader` gets its bytes from, implemented for every `BufRead` with
+/// the `std` feature and for byte slices without it
+pub trait ByteSource {
+    /// Fills `buf` completely, or fails with `Error::UnexpectedEof`
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error>;
+    /// Whether all bytes have been read
+    fn is_empty(&mut self) -> Result<bool, Error>;
+}
+
+#[cfg(feature = "std")]
+impl<R: BufRead> ByteSource for R {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        io::Read::read_exact(self, buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+            _ => Error::IO(e),
+        })
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(self.fill_buf()?.is_empty())
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl ByteSource for &[u8] {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        if self.len() < buf.len() {
+            return Err(Error::UnexpectedEof);
+        }
+        let (head, tail) = self.split_at(buf.len());
+        buf.copy_from_slice(head);
+        *self = tail;
+        Ok(())
+    }
+
+    fn is_empty(&mut self) -> Result<bool, Error> {
+        Ok(<[u8]>::is_empty(self))
+    }
 }
 
-pub struct BufferedBitstreamReader<T: Read> {
-    reader: BufReader<T>,
+pub struct BufferedBitstreamReader<T: ByteSource> {
+    reader: T,
     total_position: usize,
     curr_byte: u8,
     bit_idx: u8,
+    unread: Vec<u8>,            // In reverse order
+    recorded: Option<Vec<u8>>, // Bytes read since `start_recording`
+    crc8: u8,
+    crc16: u16,
+    diagnostics: Option<Box<dyn Diagnostics>>,
+    limits: Limits,
 }
 
-impl BufferedBitstreamReader<File> {
+#[cfg(feature = "std")]
+impl BufferedBitstreamReader<BufReader<File>> {
     pub fn open(filename: &Path) -> Result<Self, Error> {
         let file = File::open(filename)?;
-        let reader = BufReader::new(file);
+        Ok(Self::new(BufReader::new(file)))
+    }
+}
 
-        Ok(BufferedBitstreamReader {
+impl<T: ByteSource> BufferedBitstreamReader<T> {
+    pub fn new(reader: T) -> Self {
+        BufferedBitstreamReader {
             reader,
             total_position: 0,
             curr_byte: 0,
             bit_idx: 8,
-        })
+            unread: Vec::new(),
+            recorded: None,
+            crc8: 0,
+            crc16: 0,
+            diagnostics: None,
+            limits: Limits::DEFAULT,
+        }
+    }
+
+    /// Counts positions as if `total_position` bits had already been read before this reader
+    pub fn starting_at(mut self, total_position: usize) -> Self {
+        self.total_position = total_position;
+        self
+    }
+
+    /// Sets a hook that is told about everything the parsers read from this reader
+    pub fn set_diagnostics(&mut self, diagnostics: impl Diagnostics + 'static) {
+        self.diagnostics = Some(Box::new(diagnostics));
+    }
+
+    /// Sets what the parsers reading from this reader accept
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
     }
-}
 
-impl<T: Read> BufferedBitstreamReader<T> {
     #[inline(always)]
     fn refill_if_necessary(&mut self) -> Result<(), Error> {
         if self.bit_idx == 8 {
-            let mut buf = [0u8; 1];
-            self.reader.read_exact(&mut buf)?;
-            self.curr_byte = buf[0];
+            self.curr_byte = match self.unread.pop() {
+                Some(byte) => byte,
+                None => {
+                    let mut buf = [0u8; 1];
+                    self.reader.read_exact(&mut buf)?;
+                    buf[0]
+                }
+            };
+            if let Some(recorded) = &mut self.recorded {
+                recorded.push(self.curr_byte);
+            }
+            self.crc8 = crc8(self.crc8, &[self.curr_byte]);
+            self.crc16 = crc16(self.crc16, &[self.curr_byte]);
             self.bit_idx = 0;
         }
         Ok(())
     }
 }
 
-impl<T: Read> BitstreamReader for BufferedBitstreamReader<T> {
+impl<T: ByteSource> BitstreamReader for BufferedBitstreamReader<T> {
     fn read_bit(&mut self) -> Result<bool, Error> {
         self.refill_if_necessary()?;
         let result = (self.curr_byte >> (7 - self.bit_idx)) & 1 == 1;
@@ -58,23 +180,39 @@ impl<T: Read> BitstreamReader for BufferedBitstreamReader<T> {
     }
 
     fn read_bytes(&mut self, num_bytes: usize) -> Result<Box<[u8]>, Error> {
+        if num_bytes == 0 {
+            return Ok(Box::new([]));
+        }
         self.refill_if_necessary()?;
 
-        self.total_position += 8 * num_bytes;
         // fast aligned path
-        if self.bit_idx == 0 {
+        if self.bit_idx == 0 && self.unread.is_empty() {
             let mut buf = vec![0u8; num_bytes];
             self.reader.read_exact(&mut buf[1..])?;
             buf[0] = self.curr_byte;
+            if let Some(recorded) = &mut self.recorded {
+                recorded.extend_from_slice(&buf[1..]);
+            }
+            self.crc8 = crc8(self.crc8, &buf[1..]);
+            self.crc16 = crc16(self.crc16, &buf[1..]);
             self.bit_idx = 8;
+            self.total_position += 8 * num_bytes;
             Ok(buf.into_boxed_slice())
         } else {
-            unimplemented!()
+            let mut buf = Vec::with_capacity(num_bytes);
+            for _ in 0..num_bytes {
+                buf.push(self.read_unsigned(8)? as u8);
+            }
+            Ok(buf.into_boxed_slice())
         }
     }
 
     fn read_bits(&mut self, num_bits: usize) -> Result<Box<[bool]>, Error> {
-        unimplemented!("{}", num_bits)
+        let mut bits = Vec::with_capacity(num_bits);
+        for _ in 0..num_bits {
+            bits.push(self.read_bit()?);
+        }
+        Ok(bits.into_boxed_slice())
     }
 
     fn read_unary(&mut self, start: bool) -> Result<u32, Error> {
@@ -89,33 +227,47 @@ impl<T: Read> BitstreamReader for BufferedBitstreamReader<T> {
             if bit != start {
                 break;
             }
-            data += 1;
+            data = data.saturating_add(1);
         }
 
         Ok(data)
     }
 
-    fn read_utf8(&mut self, max_length: isize) -> Result<Box<str>, Error> {
-        let mut raw_data = Vec::new();
-
-        let mut cur_byte;
+    /// Reads a number in the UTF-8 like coding used by frame headers, which is extended to up to
+    /// 7 bytes and 36 bits. Overlong codings are rejected.
+    fn read_coded_number(&mut self, max_length: u8) -> Result<u64, Error> {
+        let context = Context::FrameHeader(FrameHeaderField::CodedNumber);
+        let start = Position::from_bits(self.total_position);
 
-        loop {
-            cur_byte = self.read_bytes(1)?[0];
-
-            println!("Reading utf8, got byte {}", cur_byte);
+        let first = self.read_unsigned(8)? as u8;
+        let length = match first.leading_ones() {
+            0 => return Ok(first as u64),
+            1 | 8 => return Err(Error::invalid(context, start, first as u64)),
+            n => n as u8,
+        };
+        if length > max_length {
+            return Err(Error::too_long(context, start, length as u64));
+        }
 
-            if cur_byte == 0 {
-                break;
+        // The first byte holds 7 - length bits, every continuation byte another 6
+        let mut data = (first & (0x7F >> length)) as u64;
+        for _ in 1..length {
+            let byte = self.read_unsigned(8)? as u8;
+            if byte >> 6 != 0b10 {
+                return Err(Error::invalid(context, start, byte as u64));
             }
-            raw_data.push(cur_byte);
+            data = data << 6 | (byte & 0x3F) as u64;
+        }
 
-            if raw_data.len() as isize > max_length {
-                return Err(Error::TooLong);
-            }
+        let min_value = match length {
+            2 => 0x80,
+            n => 1 << (5 * n - 4),
+        };
+        if data < min_value {
+            return Err(Error::invalid(context, start, data));
         }
 
-        Ok(String::from_utf8(raw_data)?.into_boxed_str())
+        Ok(data)
     }
 
     fn read_unsigned(&mut self, num_bits: u8) -> Result<u128, Error> {
@@ -139,12 +291,228 @@ impl<T: Read> BitstreamReader for BufferedBitstreamReader<T> {
     }
 
     fn read_signed(&mut self, num_bits: u8) -> Result<i128, Error> {
+        if num_bits == 0 {
+            return Ok(0);
+        }
         let unsigned = self.read_unsigned(num_bits)? as i128;
         let shift = (128 - num_bits) as i128;
-        Ok(((unsigned << shift) >> shift) as i128)
+        Ok((unsigned << shift) >> shift)
     }
 
     fn get_total_position(&self) -> usize {
         self.total_position
     }
+
+    fn is_at_end(&mut self) -> Result<bool, Error> {
+        Ok(self.bit_idx == 8 && self.unread.is_empty() && self.reader.is_empty()?)
+    }
+
+    fn unread_bytes(&mut self, bytes: &[u8]) {
+        debug_assert!(self.bit_idx == 8, "Cannot unread bytes while not byte aligned");
+        self.unread.extend(bytes.iter().rev());
+        self.total_position -= 8 * bytes.len();
+    }
+
+    fn start_recording(&mut self) {
+        debug_assert!(self.bit_idx == 8, "Cannot start recording while not byte aligned");
+        self.recorded = Some(Vec::new());
+    }
+
+    fn take_recorded(&mut self) -> Vec<u8> {
+        self.recorded.take().unwrap_or_default()
+    }
+
+    fn reset_crc(&mut self) {
+        debug_assert!(self.bit_idx == 8, "Cannot start a CRC while not byte aligned");
+        self.crc8 = 0;
+        self.crc16 = 0;
+    }
+
+    fn crc8(&self) -> u8 {
+        self.crc8
+    }
+
+    fn crc16(&self) -> u16 {
+        self.crc16
+    }
+
+    fn report(&mut self, event: &Event) {
+        if let Some(diagnostics) = &mut self.diagnostics {
+            diagnostics.event(event);
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+}
+
+pub trait BitstreamWriter {
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error>;
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error>;
+    fn write_unsigned(&mut self, value: u128, num_bits: u8) -> Result<(), Error>;
+    fn write_signed(&mut self, value: i128, num_bits: u8) -> Result<(), Error>;
+    fn get_total_position(&self) -> usize;
+}
+
+#[cfg(feature = "std")]
+pub struct BufferedBitstreamWriter<T: Write> {
+    writer: BufWriter<T>,
+    total_position: usize,
+    curr_byte: u8,
+    bit_idx: u8,
+}
+
+#[cfg(feature = "std")]
+impl BufferedBitstreamWriter<File> {
+    pub fn create(filename: &Path) -> Result<Self, Error> {
+        let file = File::create(filename)?;
+        Ok(Self::new(file))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Write> BufferedBitstreamWriter<T> {
+    pub fn new(writer: T) -> Self {
+        BufferedBitstreamWriter {
+            writer: BufWriter::new(writer),
+            total_position: 0,
+            curr_byte: 0,
+            bit_idx: 0,
+        }
+    }
+
+    /// Pads the current byte with zeroes, flushes and returns the underlying writer
+    pub fn finish(mut self) -> Result<T, Error> {
+        while self.bit_idx != 0 {
+            self.write_bit(false)?;
+        }
+        self.writer.into_inner().map_err(|e| e.into_error().into())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Write> BitstreamWriter for BufferedBitstreamWriter<T> {
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        self.curr_byte |= (bit as u8) << (7 - self.bit_idx);
+        self.bit_idx += 1;
+        self.total_position += 1;
+        if self.bit_idx == 8 {
+            self.writer.write_all(&[self.curr_byte])?;
+            self.curr_byte = 0;
+            self.bit_idx = 0;
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        // fast aligned path
+        if self.bit_idx == 0 {
+            self.writer.write_all(bytes)?;
+            self.total_position += 8 * bytes.len();
+        } else {
+            for byte in bytes {
+                self.write_unsigned(*byte as u128, 8)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_unsigned(&mut self, value: u128, num_bits: u8) -> Result<(), Error> {
+        debug_assert!(num_bits <= 128, "Cannot write {} bits from u128", num_bits);
+
+        for idx in (0..num_bits).rev() {
+            self.write_bit((value >> idx) & 1 == 1)?;
+        }
+        Ok(())
+    }
+
+    fn write_signed(&mut self, value: i128, num_bits: u8) -> Result<(), Error> {
+        self.write_unsigned(value as u128, num_bits)
+    }
+
+    fn get_total_position(&self) -> usize {
+        self.total_position
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_coded_number(bytes: &[u8], max_length: u8) -> Result<u64, Error> {
+        BufferedBitstreamReader::new(bytes).read_coded_number(max_length)
+    }
+
+    fn is_invalid(result: Result<u64, Error>, expected: u64) -> bool {
+        matches!(result, Err(Error::Invalid { value, .. }) if value == expected)
+    }
+
+    #[test]
+    fn coded_numbers() {
+        assert_eq!(read_coded_number(&[0x00], 6).unwrap(), 0);
+        assert_eq!(read_coded_number(&[0x7f], 6).unwrap(), 0x7f);
+        assert_eq!(read_coded_number(&[0xc2, 0x80], 6).unwrap(), 0x80);
+        assert_eq!(read_coded_number(&[0xe0, 0xa0, 0x80], 6).unwrap(), 0x800);
+        assert_eq!(
+            read_coded_number(&[0xfd, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf], 6).unwrap(),
+            0x7fff_ffff
+        );
+    }
+
+    #[test]
+    fn seven_byte_coded_number() {
+        let largest = [0xfe, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf];
+        assert_eq!(read_coded_number(&largest, 7).unwrap(), (1 << 36) - 1);
+        let smallest = [0xfe, 0x82, 0x80, 0x80, 0x80, 0x80, 0x80];
+        assert_eq!(read_coded_number(&smallest, 7).unwrap(), 1 << 31);
+
+        // Frame numbers are at most 6 bytes long
+        assert!(matches!(
+            read_coded_number(&largest, 6),
+            Err(Error::TooLong { value: 7, .. })
+        ));
+    }
+
+    #[test]
+    fn overlong_coded_numbers() {
+        assert!(is_invalid(read_coded_number(&[0xc0, 0x80], 6), 0));
+        assert!(is_invalid(read_coded_number(&[0xc1, 0xbf], 6), 0x7f));
+        assert!(is_invalid(read_coded_number(&[0xe0, 0x9f, 0xbf], 6), 0x7ff));
+        let overlong = [0xfe, 0x81, 0xbf, 0xbf, 0xbf, 0xbf, 0xbf];
+        assert!(is_invalid(read_coded_number(&overlong, 7), (1 << 31) - 1));
+    }
+
+    #[test]
+    fn invalid_coded_numbers() {
+        assert!(is_invalid(read_coded_number(&[0xff; 8], 7), 0xff));
+        assert!(is_invalid(read_coded_number(&[0x80], 7), 0x80));
+        assert!(is_invalid(read_coded_number(&[0xbf], 7), 0xbf));
+        // Continuation bytes have to start with 0b10
+        assert!(is_invalid(read_coded_number(&[0xc2, 0x41], 7), 0x41));
+        assert!(is_invalid(read_coded_number(&[0xc2, 0xc0], 7), 0xc0));
+        assert!(matches!(
+            read_coded_number(&[0xe0, 0xa0], 7),
+            Err(Error::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn rewinds_recorded_bytes() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut reader = BufferedBitstreamReader::new(&data[..]);
+        reader.read_unsigned(8).unwrap();
+
+        reader.start_recording();
+        assert_eq!(reader.read_unsigned(8).unwrap(), 1);
+        // Aligned, so this takes the fast path
+        assert_eq!(&reader.read_bytes(4).unwrap()[..], [2, 3, 4, 5]);
+        let recorded = reader.take_recorded();
+        assert_eq!(recorded, [1, 2, 3, 4, 5]);
+
+        reader.unread_bytes(&recorded);
+        assert_eq!(reader.get_total_position(), 8);
+        assert_eq!(&reader.read_bytes(9).unwrap()[..], &data[1..]);
+        assert!(reader.is_at_end().unwrap());
+    }
 }
@@ -1,45 +1,137 @@
+use alloc::vec::Vec;
 use crate::bitstream::BitstreamReader;
-use crate::error::Error;
-use crate::metadata_types::{MetadataBlock, MetadataBlockData, MetadataBlockStreamInfo};
+use crate::diagnostics::Event;
+use crate::error::{Context, Error, Position};
+use crate::metadata_types::{
+    CueSheetTrack, CueSheetTrackIndex, MetadataBlock, MetadataBlockCueSheet, MetadataBlockData,
+    MetadataBlockPicture, MetadataBlockSeekTable, MetadataBlockStreamInfo,
+    MetadataBlockVorbisComment, PictureType, SeekPoint,
+};
 
 pub fn read_magic(reader: &mut dyn BitstreamReader) -> Result<(), Error> {
     let magic = &*reader.read_bytes(4)?;
     if magic == &b"fLaC"[..] {
         Ok(())
     } else {
-        Err(Error::Content)
+        let value = magic.iter().fold(0, |acc, byte| acc << 8 | *byte as u64);
+        Err(Error::invalid(
+            Context::Magic,
+            Position::before(reader, 32),
+            value,
+        ))
     }
 }
 
+/// Reads metadata blocks up to the one marked as last, the first of which has to be STREAMINFO
+pub fn read_metadata_blocks(
+    reader: &mut dyn BitstreamReader,
+) -> Result<(MetadataBlockStreamInfo, Vec<MetadataBlock>), Error> {
+    let mut metadata = Vec::new();
+    let mut metadata_size = 0;
+    loop {
+        let block = read_nth_metadata_block(reader, metadata.len(), &mut metadata_size)?;
+        let is_last = block.is_last;
+        metadata.push(block);
+        if is_last {
+            break;
+        }
+    }
+
+    let stream_info = match &metadata[0].content {
+        MetadataBlockData::StreamInfo(si) => si.clone(),
+        _ => unreachable!(),
+    };
+    Ok((stream_info, metadata))
+}
+
+/// Reads block number `idx` of the header, which has to be STREAMINFO if it is the first.
+/// `metadata_size` is the size of the blocks before it in bytes, and has this block added to it.
+pub fn read_nth_metadata_block(
+    reader: &mut dyn BitstreamReader,
+    idx: usize,
+    metadata_size: &mut usize,
+) -> Result<MetadataBlock, Error> {
+    let start = Position::of(reader);
+    let start_bits = reader.get_total_position();
+    let max_size = reader.limits().max_metadata_size;
+    let block = read_metadata_block_within(reader, max_size.saturating_sub(*metadata_size))
+        .map_err(|e| e.within(Context::MetadataBlock(idx), start))?;
+    *metadata_size += (reader.get_total_position() - start_bits) / 8;
+
+    if idx == 0 && !matches!(block.content, MetadataBlockData::StreamInfo(_)) {
+        return Err(Error::invalid(
+            Context::MetadataBlockType,
+            start,
+            block.content.block_type() as u64,
+        ));
+    }
+    Ok(block)
+}
+
 pub fn read_metadata_block(reader: &mut dyn BitstreamReader) -> Result<MetadataBlock, Error> {
+    let max_size = reader.limits().max_metadata_size;
+    read_metadata_block_within(reader, max_size)
+}
+
+/// Reads a metadata block, failing if it is larger than `max_size` bytes including its header
+fn read_metadata_block_within(
+    reader: &mut dyn BitstreamReader,
+    max_size: usize,
+) -> Result<MetadataBlock, Error> {
     let is_last = reader.read_bit()?;
     let block_type = reader.read_unsigned(7)? as u8;
     let length = reader.read_unsigned(24)? as u32;
+    reader.report(&Event::MetadataBlock {
+        position: Position::before(reader, 32),
+        block_type,
+        length,
+        is_last,
+    });
+
+    let limits = reader.limits();
+    let length_position = Position::before(reader, 24);
+    let is_kept = matches!(block_type, 2..=6); // Everything else is skipped or fixed size
+    if 4 + length as usize > max_size || is_kept && length as usize > limits.max_item_allocation {
+        return Err(Error::too_long(
+            Context::MetadataBlockLength,
+            length_position,
+            length as u64,
+        ));
+    }
+    if block_type == 0 && length != 34 {
+        return Err(Error::invalid(
+            Context::MetadataBlockLength,
+            length_position,
+            length as u64,
+        ));
+    }
+    if block_type == 6 && length as usize > limits.max_picture_size {
+        return Err(Error::too_long(
+            Context::Picture,
+            length_position,
+            length as u64,
+        ));
+    }
+
     let data = match block_type {
         0 => MetadataBlockData::StreamInfo(read_stream_info_block(reader)?),
         1 => {
-            reader.read_bytes(length as usize)?;
-            MetadataBlockData::Padding
+            reader.skip_bytes(length as usize)?;
+            MetadataBlockData::Padding(length)
         }
         2 => MetadataBlockData::Application(reader.read_bytes(length as usize)?),
-        3 => {
-            reader.read_bytes(length as usize)?;
-            MetadataBlockData::TodoUnimplemented(block_type)
-        }
-        4 => {
-            reader.read_bytes(length as usize)?;
-            MetadataBlockData::TodoUnimplemented(block_type)
+        3 => MetadataBlockData::SeekTable(read_seek_table_block(reader, length)?),
+        4 => MetadataBlockData::VorbisComment(read_vorbis_comment_block(reader, length)?),
+        5 => MetadataBlockData::CueSheet(read_cue_sheet_block(reader, length)?),
+        6 => MetadataBlockData::Picture(read_picture_block(reader, length)?),
+        127 => {
+            reader.skip_bytes(length as usize)?;
+            MetadataBlockData::Invalid
         }
-        5 => {
-            reader.read_bytes(length as usize)?;
-            MetadataBlockData::TodoUnimplemented(block_type)
+        n => {
+            reader.skip_bytes(length as usize)?;
+            MetadataBlockData::Reserved(n)
         }
-        6 => {
-            reader.read_bytes(length as usize)?;
-            MetadataBlockData::TodoUnimplemented(block_type)
-        }
-        127 => MetadataBlockData::Invalid,
-        n => MetadataBlockData::Reserved(n),
     };
 
     Ok(MetadataBlock {
@@ -73,3 +165,196 @@ pub fn read_stream_info_block(
         decoded_checksum,
     })
 }
+
+pub fn read_seek_table_block(
+    reader: &mut dyn BitstreamReader,
+    length: u32,
+) -> Result<MetadataBlockSeekTable, Error> {
+    if !length.is_multiple_of(18) {
+        return Err(Error::invalid(
+            Context::SeekTable,
+            Position::before(reader, 24),
+            length as u64,
+        ));
+    }
+
+    let mut seek_points = Vec::new();
+    for _ in 0..length / 18 {
+        seek_points.push(SeekPoint {
+            sample_number: reader.read_unsigned(64)? as u64,
+            frame_offset: reader.read_unsigned(64)? as u64,
+            num_samples: reader.read_unsigned(16)? as u16,
+        });
+    }
+
+    Ok(MetadataBlockSeekTable {
+        seek_points: seek_points.into_boxed_slice(),
+    })
+}
+
+pub fn read_vorbis_comment_block(
+    reader: &mut dyn BitstreamReader,
+    length: u32,
+) -> Result<MetadataBlockVorbisComment, Error> {
+    let start = reader.get_total_position();
+    let remaining = |reader: &mut dyn BitstreamReader| {
+        let consumed = ((reader.get_total_position() - start) / 8) as u64;
+        (length as u64).saturating_sub(consumed)
+    };
+    // Unlike the rest of FLAC, the lengths are little-endian as in Vorbis
+    let read_length = |reader: &mut dyn BitstreamReader| -> Result<u64, Error> {
+        let bytes = reader.read_bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64)
+    };
+    // Lengths are checked against the rest of the block before allocating
+    let read_field = |reader: &mut dyn BitstreamReader| {
+        let field_length = read_length(reader)?;
+        if field_length > remaining(reader) {
+            return Err(Error::invalid(
+                Context::VorbisComment,
+                Position::before(reader, 32),
+                field_length,
+            ));
+        }
+        reader.read_bytes(field_length as usize)
+    };
+
+    let vendor = read_field(reader)?;
+    let num_comments = read_length(reader)?;
+    // Every comment takes at least its 4 byte length
+    if num_comments > remaining(reader) / 4 {
+        return Err(Error::invalid(
+            Context::VorbisComment,
+            Position::before(reader, 32),
+            num_comments,
+        ));
+    }
+    let mut comments = Vec::with_capacity(num_comments as usize);
+    for _ in 0..num_comments {
+        comments.push(read_field(reader)?);
+    }
+
+    if reader.get_total_position() - start != 8 * length as usize {
+        return Err(Error::invalid(
+            Context::VorbisComment,
+            Position::from_bits(start),
+            length as u64,
+        ));
+    }
+
+    Ok(MetadataBlockVorbisComment {
+        vendor,
+        comments: comments.into_boxed_slice(),
+    })
+}
+
+pub fn read_cue_sheet_block(
+    reader: &mut dyn BitstreamReader,
+    length: u32,
+) -> Result<MetadataBlockCueSheet, Error> {
+    let start = reader.get_total_position();
+    let mut catalog_number = reader.read_bytes(128)?.into_vec();
+    // Padded with NUL characters
+    let catalog_length = catalog_number.iter().rposition(|c| *c != 0).map_or(0, |i| i + 1);
+    catalog_number.truncate(catalog_length);
+    let num_lead_in_samples = reader.read_unsigned(64)? as u64;
+    let is_cd = reader.read_bit()?;
+    reader.read_unsigned(7)?;
+    reader.skip_bytes(258)?;
+
+    let num_tracks = reader.read_unsigned(8)? as u8;
+    let mut tracks = Vec::with_capacity(num_tracks as usize);
+    for _ in 0..num_tracks {
+        let track_offset = reader.read_unsigned(64)? as u64;
+        let track_num = reader.read_unsigned(8)? as u8;
+        let mut track_isrc = [0u8; 12];
+        track_isrc.copy_from_slice(&reader.read_bytes(12)?);
+        let track_type = reader.read_bit()?;
+        let pre_emphasis = reader.read_bit()?;
+        reader.read_unsigned(6)?;
+        reader.skip_bytes(13)?;
+
+        let num_indices = reader.read_unsigned(8)? as u8;
+        let mut indices = Vec::with_capacity(num_indices as usize);
+        for _ in 0..num_indices {
+            indices.push(CueSheetTrackIndex {
+                offset: reader.read_unsigned(64)? as u64,
+                index_point: reader.read_unsigned(8)? as u8,
+            });
+            reader.skip_bytes(3)?;
+        }
+
+        tracks.push(CueSheetTrack {
+            track_offset,
+            track_num,
+            track_isrc,
+            track_type,
+            pre_emphasis,
+            indices: indices.into_boxed_slice(),
+        });
+    }
+
+    if reader.get_total_position() - start != 8 * length as usize {
+        return Err(Error::invalid(
+            Context::CueSheet,
+            Position::from_bits(start),
+            length as u64,
+        ));
+    }
+
+    Ok(MetadataBlockCueSheet {
+        catalog_number: catalog_number.into_boxed_slice(),
+        num_lead_in_samples,
+        is_cd,
+        tracks: tracks.into_boxed_slice(),
+    })
+}
+
+pub fn read_picture_block(
+    reader: &mut dyn BitstreamReader,
+    length: u32,
+) -> Result<MetadataBlockPicture, Error> {
+    let start = reader.get_total_position();
+    // Lengths inside the block are checked against the rest of the block before allocating
+    let read_field = |reader: &mut dyn BitstreamReader| {
+        let field_length = reader.read_unsigned(32)? as u64;
+        let consumed = ((reader.get_total_position() - start) / 8) as u64;
+        let remaining = (length as u64).saturating_sub(consumed);
+        if field_length > remaining {
+            return Err(Error::invalid(
+                Context::Picture,
+                Position::before(reader, 32),
+                field_length,
+            ));
+        }
+        reader.read_bytes(field_length as usize)
+    };
+
+    let picture_type = PictureType::from(reader.read_unsigned(32)? as u32);
+    let mime_type = read_field(reader)?;
+    let description = read_field(reader)?;
+    let width = reader.read_unsigned(32)? as u32;
+    let height = reader.read_unsigned(32)? as u32;
+    let depth = reader.read_unsigned(32)? as u32;
+    let num_colors_used = reader.read_unsigned(32)? as u32;
+    let picture = read_field(reader)?;
+
+    if reader.get_total_position() - start != 8 * length as usize {
+        return Err(Error::invalid(
+            Context::Picture,
+            Position::from_bits(start),
+            length as u64,
+        ));
+    }
+
+    Ok(MetadataBlockPicture {
+        picture_type,
+        mime_type,
+        description,
+        width,
+        height,
+        depth,
+        num_colors_used,
+        picture,
+    })
+}
@@ -0,0 +1,373 @@
+use crate::bitstream::{BitstreamWriter, BufferedBitstreamWriter};
+use crate::error::{Context, Error};
+use crate::metadata_types::{
+    MetadataBlock, MetadataBlockCueSheet, MetadataBlockData, MetadataBlockPicture,
+    MetadataBlockSeekTable, MetadataBlockStreamInfo, MetadataBlockVorbisComment,
+};
+
+pub fn write_magic(writer: &mut dyn BitstreamWriter) -> Result<(), Error> {
+    writer.write_bytes(b"fLaC")
+}
+
+pub fn write_metadata_block(
+    writer: &mut dyn BitstreamWriter,
+    block: &MetadataBlock,
+) -> Result<(), Error> {
+    let block_type = block.content.block_type();
+
+    let mut body = BufferedBitstreamWriter::new(Vec::new());
+    match &block.content {
+        MetadataBlockData::StreamInfo(si) => write_stream_info_block(&mut body, si)?,
+        MetadataBlockData::Padding(length) => body.write_bytes(&vec![0u8; *length as usize])?,
+        MetadataBlockData::Application(data) => body.write_bytes(data)?,
+        MetadataBlockData::SeekTable(st) => write_seek_table_block(&mut body, st)?,
+        MetadataBlockData::VorbisComment(vc) => write_vorbis_comment_block(&mut body, vc)?,
+        MetadataBlockData::CueSheet(cs) => write_cue_sheet_block(&mut body, cs)?,
+        MetadataBlockData::Picture(pic) => write_picture_block(&mut body, pic)?,
+        MetadataBlockData::Reserved(_) | MetadataBlockData::Invalid => {
+            return Err(Error::invalid(
+                Context::MetadataBlockType,
+                None,
+                block_type as u64,
+            ))
+        }
+    }
+    let body = body.finish()?;
+
+    if body.len() >= 1 << 24 {
+        return Err(Error::too_long(
+            Context::MetadataBlockLength,
+            None,
+            body.len() as u64,
+        ));
+    }
+
+    writer.write_bit(block.is_last)?;
+    writer.write_unsigned(block_type as u128, 7)?;
+    writer.write_unsigned(body.len() as u128, 24)?;
+    writer.write_bytes(&body)
+}
+
+pub fn write_stream_info_block(
+    writer: &mut dyn BitstreamWriter,
+    stream_info: &MetadataBlockStreamInfo,
+) -> Result<(), Error> {
+    writer.write_unsigned(stream_info.min_block_size as u128, 16)?;
+    writer.write_unsigned(stream_info.max_block_size as u128, 16)?;
+    writer.write_unsigned(stream_info.min_frame_size as u128, 24)?;
+    writer.write_unsigned(stream_info.max_frame_size as u128, 24)?;
+    writer.write_unsigned(stream_info.sample_rate as u128, 20)?;
+    writer.write_unsigned(stream_info.num_channels as u128 - 1, 3)?;
+    writer.write_unsigned(stream_info.sample_depth as u128 - 1, 5)?;
+    writer.write_unsigned(stream_info.num_samples as u128, 36)?;
+    writer.write_unsigned(stream_info.decoded_checksum, 128)
+}
+
+pub fn write_seek_table_block(
+    writer: &mut dyn BitstreamWriter,
+    seek_table: &MetadataBlockSeekTable,
+) -> Result<(), Error> {
+    for point in seek_table.seek_points.iter() {
+        writer.write_unsigned(point.sample_number as u128, 64)?;
+        writer.write_unsigned(point.frame_offset as u128, 64)?;
+        writer.write_unsigned(point.num_samples as u128, 16)?;
+    }
+    Ok(())
+}
+
+pub fn write_vorbis_comment_block(
+    writer: &mut dyn BitstreamWriter,
+    vorbis_comment: &MetadataBlockVorbisComment,
+) -> Result<(), Error> {
+    // Lengths are little-endian as in Vorbis
+    let write_field = |writer: &mut dyn BitstreamWriter, field: &[u8]| {
+        if field.len() > u32::MAX as usize {
+            return Err(Error::too_long(
+                Context::VorbisComment,
+                None,
+                field.len() as u64,
+            ));
+        }
+        writer.write_bytes(&(field.len() as u32).to_le_bytes())?;
+        writer.write_bytes(field)
+    };
+
+    write_field(writer, &vorbis_comment.vendor)?;
+    if vorbis_comment.comments.len() > u32::MAX as usize {
+        return Err(Error::too_long(
+            Context::VorbisComment,
+            None,
+            vorbis_comment.comments.len() as u64,
+        ));
+    }
+    writer.write_bytes(&(vorbis_comment.comments.len() as u32).to_le_bytes())?;
+    for comment in vorbis_comment.comments.iter() {
+        write_field(writer, comment)?;
+    }
+    Ok(())
+}
+
+pub fn write_cue_sheet_block(
+    writer: &mut dyn BitstreamWriter,
+    cue_sheet: &MetadataBlockCueSheet,
+) -> Result<(), Error> {
+    if cue_sheet.catalog_number.len() > 128 {
+        return Err(Error::too_long(
+            Context::CueSheet,
+            None,
+            cue_sheet.catalog_number.len() as u64,
+        ));
+    }
+    if cue_sheet.tracks.len() > 255 {
+        return Err(Error::too_long(
+            Context::CueSheet,
+            None,
+            cue_sheet.tracks.len() as u64,
+        ));
+    }
+
+    let mut catalog_number = [0u8; 128];
+    catalog_number[..cue_sheet.catalog_number.len()].copy_from_slice(&cue_sheet.catalog_number);
+    writer.write_bytes(&catalog_number)?;
+    writer.write_unsigned(cue_sheet.num_lead_in_samples as u128, 64)?;
+    writer.write_bit(cue_sheet.is_cd)?;
+    writer.write_unsigned(0, 7)?;
+    writer.write_bytes(&[0u8; 258])?;
+    writer.write_unsigned(cue_sheet.tracks.len() as u128, 8)?;
+
+    for track in cue_sheet.tracks.iter() {
+        if track.indices.len() > 255 {
+            return Err(Error::too_long(
+                Context::CueSheet,
+                None,
+                track.indices.len() as u64,
+            ));
+        }
+
+        writer.write_unsigned(track.track_offset as u128, 64)?;
+        writer.write_unsigned(track.track_num as u128, 8)?;
+        writer.write_bytes(&track.track_isrc)?;
+        writer.write_bit(track.track_type)?;
+        writer.write_bit(track.pre_emphasis)?;
+        writer.write_unsigned(0, 6)?;
+        writer.write_bytes(&[0u8; 13])?;
+        writer.write_unsigned(track.indices.len() as u128, 8)?;
+
+        for index in track.indices.iter() {
+            writer.write_unsigned(index.offset as u128, 64)?;
+            writer.write_unsigned(index.index_point as u128, 8)?;
+            writer.write_bytes(&[0u8; 3])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn write_picture_block(
+    writer: &mut dyn BitstreamWriter,
+    picture: &MetadataBlockPicture,
+) -> Result<(), Error> {
+    writer.write_unsigned(picture.picture_type.code() as u128, 32)?;
+    writer.write_unsigned(picture.mime_type.len() as u128, 32)?;
+    writer.write_bytes(&picture.mime_type)?;
+    writer.write_unsigned(picture.description.len() as u128, 32)?;
+    writer.write_bytes(&picture.description)?;
+    writer.write_unsigned(picture.width as u128, 32)?;
+    writer.write_unsigned(picture.height as u128, 32)?;
+    writer.write_unsigned(picture.depth as u128, 32)?;
+    writer.write_unsigned(picture.num_colors_used as u128, 32)?;
+    writer.write_unsigned(picture.picture.len() as u128, 32)?;
+    writer.write_bytes(&picture.picture)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitstream::{BitstreamReader, BufferedBitstreamReader};
+    use crate::block_parser::read_metadata_block;
+    use crate::metadata_types::{CueSheetTrack, CueSheetTrackIndex, PictureType};
+
+    fn serialize(content: MetadataBlockData) -> Vec<u8> {
+        let mut writer = BufferedBitstreamWriter::new(Vec::new());
+        let block = MetadataBlock {
+            is_last: true,
+            content,
+        };
+        write_metadata_block(&mut writer, &block).unwrap();
+        writer.finish().unwrap()
+    }
+
+    /// Reads a block back and checks that writing it again gives the same bytes
+    fn round_trip(content: MetadataBlockData) -> MetadataBlockData {
+        let bytes = serialize(content);
+        let mut reader = BufferedBitstreamReader::new(&bytes[..]);
+        let block = read_metadata_block(&mut reader).unwrap();
+        assert!(block.is_last);
+        assert!(reader.is_at_end().unwrap());
+        assert_eq!(serialize(block.content.clone()), bytes);
+        block.content
+    }
+
+    #[test]
+    fn vorbis_comment() {
+        let vorbis_comment = MetadataBlockVorbisComment {
+            vendor: Box::from(&b"reference libFLAC 1.4.3 20230623"[..]),
+            comments: vec![
+                Box::from(&b"TITLE=Fish"[..]),
+                Box::from("ARTIST=Bj\u{f6}rk".as_bytes()),
+                Box::from(&b""[..]),
+            ]
+            .into_boxed_slice(),
+        };
+        let bytes = serialize(MetadataBlockData::VorbisComment(vorbis_comment.clone()));
+        // Lengths are little-endian
+        assert_eq!(bytes[4..8], [32, 0, 0, 0]);
+        assert_eq!(bytes[40..44], [3, 0, 0, 0]);
+
+        let vorbis_comment = match round_trip(MetadataBlockData::VorbisComment(vorbis_comment)) {
+            MetadataBlockData::VorbisComment(vorbis_comment) => vorbis_comment,
+            content => panic!("read back as {:?}", content),
+        };
+        assert_eq!(
+            &vorbis_comment.vendor[..],
+            b"reference libFLAC 1.4.3 20230623"
+        );
+        assert_eq!(vorbis_comment.comments.len(), 3);
+        assert_eq!(
+            &vorbis_comment.comments[1][..],
+            "ARTIST=Bj\u{f6}rk".as_bytes()
+        );
+        assert!(vorbis_comment.comments[2].is_empty());
+    }
+
+    #[test]
+    fn vorbis_comment_lengths_beyond_block() {
+        let vorbis_comment = MetadataBlockVorbisComment {
+            vendor: Box::from(&b"muflac"[..]),
+            comments: vec![Box::from(&b"A=b"[..])].into_boxed_slice(),
+        };
+        let bytes = serialize(MetadataBlockData::VorbisComment(vorbis_comment));
+        let read = |bytes: &[u8]| read_metadata_block(&mut BufferedBitstreamReader::new(bytes));
+
+        // Comment length one byte too long
+        let mut long_comment = bytes.clone();
+        long_comment[18] += 1;
+        assert!(matches!(
+            read(&long_comment),
+            Err(Error::Invalid {
+                context: Context::VorbisComment,
+                ..
+            })
+        ));
+
+        // More comments than fit into the block
+        let mut many_comments = bytes;
+        many_comments[14..18].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            read(&many_comments),
+            Err(Error::Invalid {
+                context: Context::VorbisComment,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn cue_sheet() {
+        let track = |track_num, track_offset, indices: &[(u64, u8)]| CueSheetTrack {
+            track_offset,
+            track_num,
+            track_isrc: *b"GBAYE6700012",
+            track_type: false,
+            pre_emphasis: track_num == 2,
+            indices: indices
+                .iter()
+                .map(|&(offset, index_point)| CueSheetTrackIndex {
+                    offset,
+                    index_point,
+                })
+                .collect(),
+        };
+        let cue_sheet = MetadataBlockCueSheet {
+            catalog_number: Box::from(&b"1234567890123"[..]),
+            num_lead_in_samples: 88200,
+            is_cd: true,
+            tracks: vec![
+                track(1, 0, &[(0, 1)]),
+                track(2, 441000, &[(0, 0), (588, 1)]),
+                track(170, 882000, &[]),
+            ]
+            .into_boxed_slice(),
+        };
+
+        let cue_sheet = match round_trip(MetadataBlockData::CueSheet(cue_sheet)) {
+            MetadataBlockData::CueSheet(cue_sheet) => cue_sheet,
+            content => panic!("read back as {:?}", content),
+        };
+        assert_eq!(&cue_sheet.catalog_number[..], b"1234567890123");
+        assert_eq!(cue_sheet.num_lead_in_samples, 88200);
+        assert!(cue_sheet.is_cd);
+        assert_eq!(cue_sheet.tracks.len(), 3);
+        let track = &cue_sheet.tracks[1];
+        assert_eq!((track.track_num, track.track_offset), (2, 441000));
+        assert_eq!(&track.track_isrc, b"GBAYE6700012");
+        assert!(track.pre_emphasis);
+        assert_eq!(track.indices.len(), 2);
+        assert_eq!(
+            (track.indices[1].offset, track.indices[1].index_point),
+            (588, 1)
+        );
+        assert_eq!(cue_sheet.tracks[2].track_num, 170);
+    }
+
+    #[test]
+    fn picture() {
+        let picture = MetadataBlockPicture {
+            picture_type: PictureType::BrightlyColouredFish,
+            mime_type: Box::from(&b"image/png"[..]),
+            description: Box::from("\u{1f41f}".as_bytes()),
+            width: 640,
+            height: 480,
+            depth: 24,
+            num_colors_used: 0,
+            picture: (0..=255).collect(),
+        };
+
+        let picture = match round_trip(MetadataBlockData::Picture(picture)) {
+            MetadataBlockData::Picture(picture) => picture,
+            content => panic!("read back as {:?}", content),
+        };
+        assert_eq!(picture.picture_type.code(), 17);
+        assert_eq!(&picture.mime_type[..], b"image/png");
+        assert_eq!(&picture.description[..], "\u{1f41f}".as_bytes());
+        assert_eq!(
+            (picture.width, picture.height, picture.depth),
+            (640, 480, 24)
+        );
+        assert_eq!(picture.num_colors_used, 0);
+        assert_eq!(picture.picture.len(), 256);
+        assert!(picture
+            .picture
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == i as u8));
+    }
+
+    #[test]
+    fn reserved_picture_type() {
+        let picture = MetadataBlockPicture {
+            picture_type: PictureType::Reserved(21),
+            mime_type: Box::from(&b"image/png"[..]),
+            description: Box::new([]),
+            width: 1,
+            height: 1,
+            depth: 24,
+            num_colors_used: 0,
+            picture: Box::new([0]),
+        };
+
+        match round_trip(MetadataBlockData::Picture(picture)) {
+            MetadataBlockData::Picture(picture) => assert_eq!(picture.picture_type.code(), 21),
+            content => panic!("read back as {:?}", content),
+        }
+    }
+}
@@ -0,0 +1,104 @@
+#[cfg(feature = "std")]
+const fn make_crc32_table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = (idx as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ polynomial
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+#[cfg(feature = "std")]
+const OGG_CRC32_TABLE: [u32; 256] = make_crc32_table(0x04c1_1db7);
+
+/// CRC-32 as used by Ogg pages: polynomial 0x04c11db7, zero initial value, not reflected
+#[cfg(feature = "std")]
+pub(crate) fn ogg_crc32(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        (crc << 8) ^ OGG_CRC32_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+const fn make_crc8_table(polynomial: u8) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ polynomial
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+const fn make_crc16_table(polynomial: u16) -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = (idx as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ polynomial
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+}
+
+const CRC8_TABLE: [u8; 256] = make_crc8_table(0x07);
+const CRC16_TABLE: [u16; 256] = make_crc16_table(0x8005);
+
+/// CRC-8 protecting frame headers: polynomial 0x07, zero initial value
+pub(crate) fn crc8(crc: u8, data: &[u8]) -> u8 {
+    data.iter()
+        .fold(crc, |crc, byte| CRC8_TABLE[(crc ^ byte) as usize])
+}
+
+/// CRC-16 protecting whole frames: polynomial 0x8005, zero initial value
+pub(crate) fn crc16(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |crc, byte| {
+        (crc << 8) ^ CRC16_TABLE[((crc >> 8) as u8 ^ byte) as usize]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc8(0, b"123456789"), 0xf4);
+        assert_eq!(crc16(0, b"123456789"), 0xfee8);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn ogg_check_value() {
+        assert_eq!(ogg_crc32(0, b"123456789"), 0x89a1_897f);
+    }
+}
@@ -0,0 +1,256 @@
+use crate::bitstream::BitstreamReader;
+#[cfg(feature = "std")]
+use crate::bitstream::BufferedBitstreamReader;
+use crate::block_parser::{read_magic, read_metadata_blocks};
+use crate::error::{Context, Error, Position};
+use crate::frame_parser::{find_next_frame, read_frame};
+use crate::frame_types::{
+    ChannelAssignment, ConstantSubframe, Frame, FrameOrSampleNumber, SampleNumber, Subframe,
+    SubframeData,
+};
+use crate::metadata_types::{MetadataBlock, MetadataBlockStreamInfo};
+use crate::position::{FramePosition, PositionTracker};
+use crate::sample_decoder::decode_frame;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::BufReader;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    pub frame: Frame,
+    pub position: FramePosition,
+    pub samples: Box<[Box<[i32]>]>, // One slice per channel
+    pub lost: Option<LostSamples>,  // Samples skipped right before this frame
+}
+
+/// What the decoder does when a frame cannot be decoded
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Recovery {
+    /// Return the error
+    Off,
+    /// Resume at the next valid frame and report the lost samples on it
+    Skip,
+    /// Resume at the next valid frame and return silent frames in place of the lost samples
+    Silence,
+}
+
+/// Range of samples lost to a damaged part of the stream
+#[derive(Debug, Clone)]
+pub struct LostSamples {
+    pub first_sample: u64,
+    pub num_samples: u64,
+    pub error: Arc<Error>, // The error that started the damaged range
+}
+
+/// Reads the metadata of a native FLAC stream and then iterates over its frames
+pub struct Decoder<R: BitstreamReader> {
+    reader: R,
+    stream_info: MetadataBlockStreamInfo,
+    metadata: Vec<MetadataBlock>,
+    positions: PositionTracker,
+    num_frames: u64,
+    recovery: Recovery,
+    queued: VecDeque<DecodedFrame>, // Silent frames and the frame after them
+}
+
+#[cfg(feature = "std")]
+impl Decoder<BufferedBitstreamReader<BufReader<File>>> {
+    pub fn open(filename: &Path) -> Result<Self, Error> {
+        Self::new(BufferedBitstreamReader::open(filename)?)
+    }
+}
+
+impl<R: BitstreamReader> Decoder<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        read_magic(&mut reader)?;
+        let (stream_info, metadata) = read_metadata_blocks(&mut reader)?;
+        let positions = PositionTracker::new(&stream_info);
+
+        Ok(Decoder {
+            reader,
+            stream_info,
+            metadata,
+            positions,
+            num_frames: 0,
+            recovery: Recovery::Off,
+            queued: VecDeque::new(),
+        })
+    }
+
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        &self.stream_info
+    }
+
+    /// All metadata blocks, starting with STREAMINFO
+    pub fn metadata(&self) -> &[MetadataBlock] {
+        &self.metadata
+    }
+
+    pub fn set_recovery(&mut self, recovery: Recovery) {
+        self.recovery = recovery;
+    }
+
+    /// Returns the next frame with its position in the stream, or `None` at the end of the stream
+    pub fn next_frame(&mut self) -> Result<Option<DecodedFrame>, Error> {
+        if let Some(frame) = self.queued.pop_front() {
+            return Ok(Some(frame));
+        }
+
+        let mut damage: Option<(u64, Error)> = None;
+        loop {
+            if self.reader.is_at_end()? {
+                return match damage {
+                    Some((first_sample, error)) => self.finish_damaged(first_sample, error),
+                    None => Ok(None),
+                };
+            }
+
+            let start = Position::of(&self.reader);
+            let frame_idx = self.num_frames;
+            self.num_frames += 1;
+            if self.recovery != Recovery::Off {
+                self.reader.start_recording();
+            }
+            let result = read_frame(&mut self.reader, &self.stream_info);
+            let recorded = self.reader.take_recorded();
+            match result {
+                Ok(frame) => return Ok(Some(self.resume(frame, damage))),
+                Err(e) => {
+                    let e = e.within(Context::Frame(frame_idx), start);
+                    // Only damage in the stream is recovered from, not failures to read it
+                    let recoverable = match e.innermost() {
+                        #[cfg(feature = "std")]
+                        Error::IO(_) => false,
+                        _ => true,
+                    };
+                    if self.recovery == Recovery::Off || !recoverable {
+                        return Err(e);
+                    }
+
+                    // Later errors are part of the same damaged range
+                    if damage.is_none() {
+                        damage = Some((self.positions.expected_sample(), e));
+                    }
+                    // The damaged frame may have run into the next one, so search from the byte
+                    // after its start instead of from where parsing failed
+                    while !self.reader.get_total_position().is_multiple_of(8) {
+                        self.reader.read_bit()?;
+                    }
+                    self.reader
+                        .unread_bytes(recorded.get(1..).unwrap_or_default());
+                    if !find_next_frame(&mut self.reader)? {
+                        let (first_sample, error) = damage.unwrap();
+                        return self.finish_damaged(first_sample, error);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Builds the frame decoded after a damaged range, queueing silence in front of it if needed
+    fn resume(&mut self, frame: Frame, damage: Option<(u64, Error)>) -> DecodedFrame {
+        let lost = damage.map(|(first_sample, error)| LostSamples {
+            first_sample,
+            num_samples: self
+                .positions
+                .first_sample(&frame)
+                .saturating_sub(first_sample),
+            error: Arc::new(error),
+        });
+
+        let lost = match lost {
+            Some(lost) if self.recovery == Recovery::Silence && lost.num_samples > 0 => {
+                self.queue_silence(&lost);
+                None
+            }
+            lost => lost,
+        };
+
+        let position = self.positions.track(&frame);
+        let samples = decode_frame(&frame);
+        let decoded = DecodedFrame {
+            frame,
+            position,
+            samples,
+            lost,
+        };
+
+        match self.queued.pop_front() {
+            Some(silence) => {
+                self.queued.push_back(decoded);
+                silence
+            }
+            None => decoded,
+        }
+    }
+
+    /// Handles a damaged range that lasts until the end of the stream
+    fn finish_damaged(
+        &mut self,
+        first_sample: u64,
+        error: Error,
+    ) -> Result<Option<DecodedFrame>, Error> {
+        let num_samples = self.stream_info.num_samples;
+        if self.recovery != Recovery::Silence || num_samples <= first_sample {
+            return Err(error);
+        }
+
+        self.queue_silence(&LostSamples {
+            first_sample,
+            num_samples: num_samples - first_sample,
+            error: Arc::new(error),
+        });
+        Ok(self.queued.pop_front())
+    }
+
+    fn queue_silence(&mut self, lost: &LostSamples) {
+        let max_block_size = match self.stream_info.max_block_size {
+            0 => 4096,
+            size => size as u64,
+        };
+
+        let end = lost.first_sample + lost.num_samples;
+        let mut first_sample = lost.first_sample;
+        while first_sample < end {
+            let block_size = max_block_size.min(end - first_sample) as u32;
+            let frame = self.silent_frame(first_sample, block_size);
+            let position = self.positions.track(&frame);
+            let samples = decode_frame(&frame);
+            self.queued.push_back(DecodedFrame {
+                frame,
+                position,
+                samples,
+                lost: Some(lost.clone()),
+            });
+            first_sample += block_size as u64;
+        }
+    }
+
+    fn silent_frame(&self, first_sample: u64, block_size: u32) -> Frame {
+        let subframe = Subframe {
+            wasted_bits: 0,
+            data: SubframeData::Constant(ConstantSubframe { content: 0 }),
+        };
+
+        Frame {
+            is_variable: true,
+            block_size,
+            sample_rate: self.stream_info.sample_rate,
+            num_channels: self.stream_info.num_channels,
+            channel_assignment: ChannelAssignment::Direct,
+            sample_depth: self.stream_info.sample_depth,
+            frame_or_sample_number: FrameOrSampleNumber::Sample(SampleNumber(first_sample)),
+            header_crc: 0,
+            subframes: vec![subframe; self.stream_info.num_channels as usize].into_boxed_slice(),
+            overall_crc: 0,
+        }
+    }
+}
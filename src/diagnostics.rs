@@ -0,0 +1,76 @@
+use crate::error::Position;
+use crate::frame_types::{ChannelAssignment, FrameOrSampleNumber, SubframeType};
+
+/// Something a parser has just read, reported to the `Diagnostics` hook of the reader
+#[derive(Debug, Copy, Clone)]
+pub enum Event {
+    MetadataBlock {
+        position: Position,
+        block_type: u8,
+        length: u32,
+        is_last: bool,
+    },
+    /// Reported once the header CRC-8 has been checked
+    FrameHeader {
+        position: Position,
+        is_variable: bool,
+        block_size: u32,
+        sample_rate: u32,
+        num_channels: u8,
+        channel_assignment: ChannelAssignment,
+        sample_depth: u8,
+        frame_or_sample_number: FrameOrSampleNumber,
+    },
+    Subframe {
+        position: Position,
+        channel: u8,
+        subframe_type: SubframeType,
+        wasted_bits: u8,
+    },
+    Residual {
+        position: Position,
+        parameter_size: u8, // RICE is 4, RICE2 = 5
+        partition_order: u8,
+    },
+    ResidualPartition {
+        position: Position,
+        index: u16,
+        encoding_parameter: u8,
+        escape_bits: Option<u8>, // Bits per sample of an escaped partition
+    },
+}
+
+/// Receives parser events, any `FnMut(&Event)` can be used as a hook
+pub trait Diagnostics {
+    fn event(&mut self, event: &Event);
+}
+
+impl<F: FnMut(&Event)> Diagnostics for F {
+    fn event(&mut self, event: &Event) {
+        self(event)
+    }
+}
+
+/// Forwards events to the `log` crate at trace level
+#[cfg(feature = "log")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct LogDiagnostics;
+
+#[cfg(feature = "log")]
+impl Diagnostics for LogDiagnostics {
+    fn event(&mut self, event: &Event) {
+        log::trace!("{:?}", event);
+    }
+}
+
+/// Forwards events to the `tracing` crate as trace level events
+#[cfg(feature = "tracing")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct TracingDiagnostics;
+
+#[cfg(feature = "tracing")]
+impl Diagnostics for TracingDiagnostics {
+    fn event(&mut self, event: &Event) {
+        tracing::trace!(?event);
+    }
+}
@@ -1,17 +1,318 @@
+use crate::bitstream::BitstreamReader;
+use alloc::boxed::Box;
+use alloc::string::{FromUtf8Error, String};
+use core::fmt;
+#[cfg(feature = "std")]
 use std::io;
-use std::num::ParseIntError;
-use std::string::FromUtf8Error;
 
 #[derive(Debug)]
 pub enum Error {
+    #[cfg(feature = "std")]
     IO(io::Error),
+    /// The input ended in the middle of something
+    UnexpectedEof,
     UTF8(FromUtf8Error),
-    ParseInt(ParseIntError),
-    TooLong,
-    Content,
+    /// A value the format does not allow here
+    Invalid {
+        context: Context,
+        position: Option<Position>,
+        value: u64,
+    },
+    /// A value the format reserves for future use
+    Reserved {
+        context: Context,
+        position: Option<Position>,
+        value: u64,
+    },
+    /// A length or count larger than the format allows
+    TooLong {
+        context: Context,
+        position: Option<Position>,
+        value: u64,
+    },
+    /// Something required is not present
+    Missing {
+        context: Context,
+        position: Option<Position>,
+    },
+    /// Another error that happened while parsing `context`, which starts at `position`
+    Within {
+        context: Context,
+        position: Option<Position>,
+        source: Box<Error>,
+    },
+    /// A decoding thread panicked, with the panic message
+    #[cfg(feature = "std")]
+    Panicked(String),
+}
+
+/// What was being parsed when an error happened
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Context {
+    Magic,
+    MetadataBlock(usize), // Index of the block in the stream
+    MetadataBlockType,
+    MetadataBlockLength,
+    StreamInfo,
+    SeekTable,
+    VorbisComment,
+    CueSheet,
+    Picture,
+    Frame(u64), // Index of the frame in the stream
+    FrameHeader(FrameHeaderField),
+    FrameFooter,
+    Subframe(u8), // Channel
+    SubframeHeader,
+    SubframeType,
+    WastedBits,
+    LPCPrecision,
+    LPCShift,
+    ResidualCodingMethod,
+    Residual,
+    PartitionOrder,
+    ResidualPartition(u16),
+    OggHeader,
+    EBMLInteger,
+    MatroskaElement(u32), // Element ID
+    MatroskaBlock,
+    Mp4Box([u8; 4]),
+    Mp4Track,
+    Mp4Sample(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameHeaderField {
+    SyncCode,
+    BlockingStrategy,
+    BlockSize,
+    SampleRate,
+    ChannelAssignment,
+    SampleDepth,
+    CodedNumber,
     Reserved,
+    CRC,
+}
+
+/// Offset in the stream, as counted by `BitstreamReader::get_total_position`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    pub byte: usize,
+    pub bit: u8,
+}
+
+impl Position {
+    pub fn from_bits(total_bits: usize) -> Self {
+        Position {
+            byte: total_bits / 8,
+            bit: (total_bits % 8) as u8,
+        }
+    }
+
+    /// Position of a value of `num_bits` bits which the reader has just read
+    pub fn before(reader: &dyn BitstreamReader, num_bits: usize) -> Self {
+        Self::from_bits(reader.get_total_position().saturating_sub(num_bits))
+    }
+
+    pub fn of(reader: &dyn BitstreamReader) -> Self {
+        Self::from_bits(reader.get_total_position())
+    }
+}
+
+impl Error {
+    pub fn invalid(context: Context, position: impl Into<Option<Position>>, value: u64) -> Self {
+        Error::Invalid {
+            context,
+            position: position.into(),
+            value,
+        }
+    }
+
+    pub fn reserved(context: Context, position: impl Into<Option<Position>>, value: u64) -> Self {
+        Error::Reserved {
+            context,
+            position: position.into(),
+            value,
+        }
+    }
+
+    pub fn too_long(context: Context, position: impl Into<Option<Position>>, value: u64) -> Self {
+        Error::TooLong {
+            context,
+            position: position.into(),
+            value,
+        }
+    }
+
+    pub fn missing(context: Context, position: impl Into<Option<Position>>) -> Self {
+        Error::Missing {
+            context,
+            position: position.into(),
+        }
+    }
+
+    /// Wraps the error to record that it happened inside `context`
+    pub fn within(self, context: Context, position: impl Into<Option<Position>>) -> Self {
+        Error::Within {
+            context,
+            position: position.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The error at the end of the `Within` chain
+    pub fn innermost(&self) -> &Error {
+        match self {
+            Error::Within { source, .. } => source.innermost(),
+            err => err,
+        }
+    }
+
+    /// Position of the innermost error, if known
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::Invalid { position, .. }
+            | Error::Reserved { position, .. }
+            | Error::TooLong { position, .. }
+            | Error::Missing { position, .. } => *position,
+            Error::Within {
+                position, source, ..
+            } => source.position().or(*position),
+            _ => None,
+        }
+    }
+
+    /// Whether the error was caused by the input ending early
+    pub fn is_eof(&self) -> bool {
+        match self.innermost() {
+            Error::UnexpectedEof => true,
+            #[cfg(feature = "std")]
+            Error::IO(err) => err.kind() == io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            Error::IO(_) => write!(f, "I/O error"),
+            Error::UnexpectedEof => write!(f, "unexpected end of stream"),
+            Error::UTF8(_) => write!(f, "invalid UTF-8"),
+            Error::Invalid {
+                context,
+                position,
+                value,
+            } => {
+                write!(f, "invalid value {} in {}", value, context)?;
+                write_position(f, position)
+            }
+            Error::Reserved {
+                context,
+                position,
+                value,
+            } => {
+                write!(f, "reserved value {} in {}", value, context)?;
+                write_position(f, position)
+            }
+            Error::TooLong {
+                context,
+                position,
+                value,
+            } => {
+                write!(f, "{} too long ({})", context, value)?;
+                write_position(f, position)
+            }
+            Error::Missing { context, position } => {
+                write!(f, "missing {}", context)?;
+                write_position(f, position)
+            }
+            Error::Within {
+                context, position, ..
+            } => {
+                write!(f, "error in {}", context)?;
+                write_position(f, position)
+            }
+            #[cfg(feature = "std")]
+            Error::Panicked(message) => write!(f, "decoding thread panicked: {}", message),
+        }
+    }
+}
+
+fn write_position(f: &mut fmt::Formatter<'_>, position: &Option<Position>) -> fmt::Result {
+    match position {
+        Some(position) => write!(f, " at byte {}, bit {}", position.byte, position.bit),
+        None => Ok(()),
+    }
 }
 
+impl fmt::Display for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Context::Magic => write!(f, "magic number"),
+            Context::MetadataBlock(idx) => write!(f, "metadata block {}", idx),
+            Context::MetadataBlockType => write!(f, "metadata block type"),
+            Context::MetadataBlockLength => write!(f, "metadata block length"),
+            Context::StreamInfo => write!(f, "STREAMINFO"),
+            Context::SeekTable => write!(f, "SEEKTABLE"),
+            Context::VorbisComment => write!(f, "VORBIS_COMMENT"),
+            Context::CueSheet => write!(f, "CUESHEET"),
+            Context::Picture => write!(f, "PICTURE"),
+            Context::Frame(idx) => write!(f, "frame {}", idx),
+            Context::FrameHeader(field) => write!(f, "frame header {}", field),
+            Context::FrameFooter => write!(f, "frame CRC-16"),
+            Context::Subframe(channel) => write!(f, "subframe {}", channel),
+            Context::SubframeHeader => write!(f, "subframe header"),
+            Context::SubframeType => write!(f, "subframe type"),
+            Context::WastedBits => write!(f, "wasted bits"),
+            Context::LPCPrecision => write!(f, "LPC coefficient precision"),
+            Context::LPCShift => write!(f, "LPC shift"),
+            Context::ResidualCodingMethod => write!(f, "residual coding method"),
+            Context::Residual => write!(f, "residual"),
+            Context::PartitionOrder => write!(f, "residual partition order"),
+            Context::ResidualPartition(idx) => write!(f, "residual partition {}", idx),
+            Context::OggHeader => write!(f, "Ogg FLAC header"),
+            Context::EBMLInteger => write!(f, "EBML variable size integer"),
+            Context::MatroskaElement(id) => write!(f, "Matroska element {:#x}", id),
+            Context::MatroskaBlock => write!(f, "Matroska block"),
+            Context::Mp4Box(box_type) => {
+                write!(f, "MP4 box '{}'", String::from_utf8_lossy(box_type))
+            }
+            Context::Mp4Track => write!(f, "MP4 track"),
+            Context::Mp4Sample(idx) => write!(f, "MP4 sample {}", idx),
+        }
+    }
+}
+
+impl fmt::Display for FrameHeaderField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FrameHeaderField::SyncCode => "sync code",
+            FrameHeaderField::BlockingStrategy => "blocking strategy",
+            FrameHeaderField::BlockSize => "block size",
+            FrameHeaderField::SampleRate => "sample rate",
+            FrameHeaderField::ChannelAssignment => "channel assignment",
+            FrameHeaderField::SampleDepth => "sample depth",
+            FrameHeaderField::CodedNumber => "frame/sample number",
+            FrameHeaderField::Reserved => "reserved bit",
+            FrameHeaderField::CRC => "CRC-8",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(err) => Some(err),
+            Error::UTF8(err) => Some(err),
+            Error::Within { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
         Error::IO(err)
@@ -23,9 +324,3 @@ impl From<FromUtf8Error> for Error {
         Error::UTF8(err)
     }
 }
-
-impl From<ParseIntError> for Error {
-    fn from(err: ParseIntError) -> Self {
-        Error::ParseInt(err)
-    }
-}
@@ -0,0 +1,129 @@
+use crate::bitstream::BitstreamReader;
+#[cfg(feature = "std")]
+use crate::bitstream::BufferedBitstreamReader;
+use crate::block_parser::{read_magic, read_metadata_blocks};
+use crate::error::{Context, Error, Position};
+use crate::frame_parser::read_frame;
+use crate::frame_types::{ChannelAssignment, Frame, SubframeType};
+use crate::metadata_types::{MetadataBlock, MetadataBlockStreamInfo};
+use crate::position::PositionTracker;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::fs::File;
+#[cfg(feature = "std")]
+use std::io::BufReader;
+#[cfg(feature = "std")]
+use std::path::Path;
+
+/// Where a frame is stored and how it is coded
+#[derive(Debug, Clone)]
+pub struct FrameIndexEntry {
+    pub offset: u64, // In bytes from the start of the stream, including the magic
+    pub size: u32,   // In bytes, including header and CRC-16
+    pub first_sample: u64,
+    pub block_size: u32,
+    pub sample_rate: u32,
+    pub channel_assignment: ChannelAssignment,
+    pub subframe_types: Box<[Option<SubframeType>]>,
+}
+
+impl FrameIndexEntry {
+    /// Bits per second spent on this frame
+    pub fn bitrate(&self) -> f64 {
+        if self.block_size == 0 {
+            return 0.0;
+        }
+        8.0 * self.size as f64 * self.sample_rate as f64 / self.block_size as f64
+    }
+}
+
+/// Walks the frames of a native FLAC stream, parsing them without reconstructing samples
+pub struct FrameIndex<R: BitstreamReader> {
+    reader: R,
+    stream_info: MetadataBlockStreamInfo,
+    metadata: Vec<MetadataBlock>,
+    positions: PositionTracker,
+    num_frames: u64,
+    failed: bool,
+}
+
+#[cfg(feature = "std")]
+impl FrameIndex<BufferedBitstreamReader<BufReader<File>>> {
+    pub fn open(filename: &Path) -> Result<Self, Error> {
+        Self::new(BufferedBitstreamReader::open(filename)?)
+    }
+}
+
+impl<R: BitstreamReader> FrameIndex<R> {
+    pub fn new(mut reader: R) -> Result<Self, Error> {
+        read_magic(&mut reader)?;
+        let (stream_info, metadata) = read_metadata_blocks(&mut reader)?;
+        let positions = PositionTracker::new(&stream_info);
+
+        Ok(FrameIndex {
+            reader,
+            stream_info,
+            metadata,
+            positions,
+            num_frames: 0,
+            failed: false,
+        })
+    }
+
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        &self.stream_info
+    }
+
+    /// All metadata blocks, starting with STREAMINFO
+    pub fn metadata(&self) -> &[MetadataBlock] {
+        &self.metadata
+    }
+
+    /// Returns the location of the next frame, or `None` at the end of the stream
+    pub fn next_entry(&mut self) -> Result<Option<FrameIndexEntry>, Error> {
+        Ok(self.next_frame()?.map(|(entry, _)| entry))
+    }
+
+    /// Returns the location of the next frame along with the parsed frame
+    pub fn next_frame(&mut self) -> Result<Option<(FrameIndexEntry, Frame)>, Error> {
+        if self.reader.is_at_end()? {
+            return Ok(None);
+        }
+
+        let start = self.reader.get_total_position();
+        let frame = read_frame(&mut self.reader, &self.stream_info)
+            .map_err(|e| e.within(Context::Frame(self.num_frames), Position::from_bits(start)))?;
+        self.num_frames += 1;
+        let position = self.positions.track(&frame);
+
+        let entry = FrameIndexEntry {
+            offset: (start / 8) as u64,
+            size: ((self.reader.get_total_position() - start) / 8) as u32,
+            first_sample: position.first_sample,
+            block_size: frame.block_size,
+            sample_rate: frame.sample_rate,
+            channel_assignment: frame.channel_assignment,
+            subframe_types: frame
+                .subframes
+                .iter()
+                .map(|subframe| subframe.data.subframe_type())
+                .collect(),
+        };
+        Ok(Some((entry, frame)))
+    }
+}
+
+/// Stops after the first error, since the frame boundaries after it are unknown
+impl<R: BitstreamReader> Iterator for FrameIndex<R> {
+    type Item = Result<FrameIndexEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let entry = self.next_entry();
+        self.failed = entry.is_err();
+        entry.transpose()
+    }
+}
@@ -1,24 +1,46 @@
 use crate::bitstream::BitstreamReader;
-use crate::error::Error;
+use crate::crc::crc8;
+use crate::diagnostics::Event;
+use crate::error::{Context, Error, FrameHeaderField, Position};
 use crate::frame_types::{
-    ChannelAssignment, ConstantSubframe, FixedSubframe, Frame, LPCSubframe, RICEPartition,
-    Residual, Subframe, SubframeData, VerbatimSubframe,
+    ChannelAssignment, ConstantSubframe, FixedSubframe, Frame, FrameNumber, FrameOrSampleNumber,
+    LPCSubframe, RICEPartition, Residual, SampleNumber, Subframe, SubframeData, SubframeType,
+    VerbatimSubframe,
 };
+use crate::limits::Limits;
 use crate::metadata_types::MetadataBlockStreamInfo;
-use std::str::FromStr;
 use crate::rice::read_rice;
+use alloc::vec::Vec;
 
 pub fn read_frame(
     reader: &mut dyn BitstreamReader,
     stream_info: &MetadataBlockStreamInfo,
 ) -> Result<Frame, Error> {
+    reader.reset_crc();
+    let start = reader.get_total_position();
+    // Errors in the fixed part of the header point at the offending field
+    let invalid = |field, offset, value| {
+        Error::invalid(
+            Context::FrameHeader(field),
+            Position::from_bits(start + offset),
+            value as u64,
+        )
+    };
+    let reserved = |field, offset, value| {
+        Error::reserved(
+            Context::FrameHeader(field),
+            Position::from_bits(start + offset),
+            value as u64,
+        )
+    };
+
     let sync_code = reader.read_unsigned(14)?;
     if sync_code != 0b11_1111_1111_1110 {
-        return Err(Error::Content);
+        return Err(invalid(FrameHeaderField::SyncCode, 0, sync_code));
     }
 
     if reader.read_bit()? {
-        return Err(Error::Reserved);
+        return Err(reserved(FrameHeaderField::Reserved, 14, 1));
     }
 
     let is_variable = reader.read_bit()?;
@@ -28,19 +50,22 @@ pub fn read_frame(
     let sample_depth_raw = reader.read_unsigned(3)? as u8;
 
     if reader.read_bit()? {
-        return Err(Error::Reserved);
+        return Err(reserved(FrameHeaderField::Reserved, 31, 1));
     }
 
-    let frame_or_sample_number =
-        u64::from_str(&*reader.read_utf8(if is_variable { 5 } else { 4 })?).ok();
+    let frame_or_sample_number = if is_variable {
+        FrameOrSampleNumber::Sample(SampleNumber(reader.read_coded_number(7)?))
+    } else {
+        FrameOrSampleNumber::Frame(FrameNumber(reader.read_coded_number(6)? as u32))
+    };
 
     let block_size = match block_size_raw {
-        0b0000 => Err(Error::Reserved),
+        0b0000 => Err(reserved(FrameHeaderField::BlockSize, 16, 0)),
         0b0001 => Ok(192),
         0b0110 => Ok(reader.read_unsigned(8)? as u32 + 1),
         0b0111 => Ok(reader.read_unsigned(16)? as u32 + 1),
-        x if x >= 0b0010 && x <= 0b0101 => Ok(576 << (x - 2)),
-        x if x >= 0b1000 && x <= 0b1111 => Ok(256 << (x - 8)),
+        x @ 0b0010..=0b0101 => Ok(576 << (x - 2)),
+        x @ 0b1000..=0b1111 => Ok(256 << (x - 8)),
         _ => unreachable!(),
     }?;
 
@@ -60,7 +85,7 @@ pub fn read_frame(
         0b1100 => Ok(reader.read_unsigned(8)? as u32),
         0b1101 => Ok(reader.read_unsigned(16)? as u32),
         0b1110 => Ok(reader.read_unsigned(16)? as u32 * 10),
-        0b1111 => Err(Error::Reserved),
+        0b1111 => Err(reserved(FrameHeaderField::SampleRate, 20, 0b1111)),
         _ => unreachable!(),
     }?;
 
@@ -68,8 +93,8 @@ pub fn read_frame(
         0b1000 => Ok(ChannelAssignment::LeftSide),
         0b1001 => Ok(ChannelAssignment::RightSide),
         0b1010 => Ok(ChannelAssignment::MidSide),
-        x if x <= 0b0111 => Ok(ChannelAssignment::Direct),
-        x if x >= 0b1011 && x <= 0b1111 => Err(Error::Reserved),
+        0b0000..=0b0111 => Ok(ChannelAssignment::Direct),
+        x @ 0b1011..=0b1111 => Err(reserved(FrameHeaderField::ChannelAssignment, 24, x)),
         _ => unreachable!(),
     }?;
 
@@ -83,22 +108,70 @@ pub fn read_frame(
         0b000 => Ok(stream_info.sample_depth),
         0b001 => Ok(8),
         0b010 => Ok(12),
-        0b011 => Err(Error::Reserved),
+        0b011 => Err(reserved(FrameHeaderField::SampleDepth, 28, 0b011)),
         0b100 => Ok(16),
         0b101 => Ok(24),
         0b110 => Ok(32),
-        0b111 => Err(Error::Reserved),
+        0b111 => Err(reserved(FrameHeaderField::SampleDepth, 28, 0b111)),
         _ => unreachable!(),
     }?;
 
+    let computed_header_crc = reader.crc8();
     let header_crc = reader.read_unsigned(8)? as u8;
+    if header_crc != computed_header_crc {
+        return Err(Error::invalid(
+            Context::FrameHeader(FrameHeaderField::CRC),
+            Position::before(reader, 8),
+            header_crc as u64,
+        ));
+    }
+
+    let limits = reader.limits();
+    if block_size > limits.max_block_size
+        || Limits::frame_allocation(block_size, num_channels) > limits.max_item_allocation
+    {
+        return Err(Error::too_long(
+            Context::FrameHeader(FrameHeaderField::BlockSize),
+            Position::from_bits(start + 16),
+            block_size as u64,
+        ));
+    }
+    reader.report(&Event::FrameHeader {
+        position: Position::from_bits(start),
+        is_variable,
+        block_size,
+        sample_rate,
+        num_channels,
+        channel_assignment,
+        sample_depth,
+        frame_or_sample_number,
+    });
 
     let mut subframes = Vec::new();
-    for i in 0..num_channels {
-        subframes.push(read_subframe(reader, sample_depth, block_size)?);
+    for channel in 0..num_channels {
+        // The side channel needs an extra bit to hold the difference
+        let is_side = channel_assignment.side_channel() == Some(channel as usize);
+        let subframe_depth = sample_depth + is_side as u8;
+        let subframe_start = Position::of(reader);
+        let subframe = read_subframe(reader, channel, subframe_depth, block_size)
+            .map_err(|e| e.within(Context::Subframe(channel), subframe_start))?;
+        subframes.push(subframe);
+    }
+
+    // Subframes are not byte aligned, the footer is
+    while !reader.get_total_position().is_multiple_of(8) {
+        reader.read_bit()?;
     }
 
+    let computed_crc = reader.crc16();
     let overall_crc = reader.read_unsigned(16)? as u16;
+    if overall_crc != computed_crc {
+        return Err(Error::invalid(
+            Context::FrameFooter,
+            Position::before(reader, 16),
+            overall_crc as u64,
+        ));
+    }
 
     Ok(Frame {
         is_variable,
@@ -114,23 +187,142 @@ pub fn read_frame(
     })
 }
 
+/// Skips forward to the next byte that starts a plausible frame header with a valid CRC-8,
+/// leaving the reader positioned there. Returns false if the stream ends first.
+pub fn find_next_frame(reader: &mut dyn BitstreamReader) -> Result<bool, Error> {
+    while !reader.get_total_position().is_multiple_of(8) {
+        reader.read_bit()?;
+    }
+
+    let mut window = Vec::new();
+    loop {
+        let length = match window.get(..5) {
+            Some(fixed) if window[0] == 0xFF && window[1] & 0xFE == 0xF8 => {
+                frame_header_length(fixed)
+            }
+            _ if window.len() < 5 => {
+                if reader.is_at_end()? {
+                    return Ok(false);
+                }
+                window.push(reader.read_unsigned(8)? as u8);
+                continue;
+            }
+            _ => None,
+        };
+
+        if let Some(length) = length {
+            while window.len() < length && !reader.is_at_end()? {
+                window.push(reader.read_unsigned(8)? as u8);
+            }
+            if is_frame_header(&window) {
+                reader.unread_bytes(&window);
+                return Ok(true);
+            }
+        }
+        window.remove(0);
+    }
+}
+
+/// Whether `bytes` start with a plausible frame header with a valid CRC-8
+pub(crate) fn is_frame_header(bytes: &[u8]) -> bool {
+    if bytes.len() < 5 || bytes[0] != 0xFF || bytes[1] & 0xFE != 0xF8 {
+        return false;
+    }
+    match frame_header_length(bytes) {
+        Some(length) if bytes.len() >= length => crc8(0, &bytes[..length - 1]) == bytes[length - 1],
+        _ => false,
+    }
+}
+
+/// Length of a frame header including its CRC, based on its first five bytes, or None if
+/// those bytes cannot start a frame header
+fn frame_header_length(header: &[u8]) -> Option<usize> {
+    let block_size_raw = header[2] >> 4;
+    let sample_rate_raw = header[2] & 0xF;
+    let channel_assignment_raw = header[3] >> 4;
+    let sample_depth_raw = (header[3] >> 1) & 0b111;
+    if block_size_raw == 0
+        || sample_rate_raw == 0b1111
+        || channel_assignment_raw >= 0b1011
+        || sample_depth_raw == 0b011
+        || sample_depth_raw == 0b111
+        || header[3] & 1 != 0
+    {
+        return None;
+    }
+
+    let coded_number_length = match header[4].leading_ones() {
+        0 => 1,
+        1 | 8 => return None,
+        n => n as usize,
+    };
+    let block_size_length = match block_size_raw {
+        0b0110 => 1,
+        0b0111 => 2,
+        _ => 0,
+    };
+    let sample_rate_length = match sample_rate_raw {
+        0b1100 => 1,
+        0b1101 | 0b1110 => 2,
+        _ => 0,
+    };
+
+    Some(4 + coded_number_length + block_size_length + sample_rate_length + 1)
+}
+
 fn read_subframe(
     reader: &mut dyn BitstreamReader,
+    channel: u8,
     sample_depth: u8,
     block_size: u32,
 ) -> Result<Subframe, Error> {
+    let start = Position::of(reader);
     if reader.read_bit()? {
-        return Err(Error::Content);
+        return Err(Error::invalid(
+            Context::SubframeHeader,
+            Position::before(reader, 1),
+            1,
+        ));
     }
 
     let subframe_type = reader.read_unsigned(6)? as u8;
+    let type_position = Position::before(reader, 6);
+    let reserved = || Error::reserved(Context::SubframeType, type_position, subframe_type as u64);
 
     let wasted_bits = if reader.read_bit()? {
-        reader.read_unary(false)? as u8 + 1
+        let wasted_bits = reader.read_unary(false)?.saturating_add(1);
+        if wasted_bits >= sample_depth as u32 {
+            return Err(Error::invalid(
+                Context::WastedBits,
+                Position::before(reader, wasted_bits as usize),
+                wasted_bits as u64,
+            ));
+        }
+        wasted_bits as u8
     } else {
         0
     };
 
+    let reported_type = match subframe_type {
+        0b00_0000 => Some(SubframeType::Constant),
+        0b00_0001 => Some(SubframeType::Verbatim),
+        x @ 0b00_1000..=0b00_1100 => Some(SubframeType::Fixed { order: x & 0b111 }),
+        x @ 0b10_0000..=0b11_1111 => Some(SubframeType::LPC {
+            order: (x & 0b01_1111) + 1,
+        }),
+        _ => None,
+    };
+    if let Some(subframe_type) = reported_type {
+        reader.report(&Event::Subframe {
+            position: start,
+            channel,
+            subframe_type,
+            wasted_bits,
+        });
+    }
+    // Wasted bits are shifted out before encoding, so the subframe is narrower
+    let sample_depth = sample_depth - wasted_bits;
+
     let data = match subframe_type {
         0b00000 => Ok(SubframeData::Constant(read_constant_subframe(
             reader,
@@ -141,23 +333,21 @@ fn read_subframe(
             sample_depth,
             block_size,
         )?)),
-        x if x >= 0b00_0010 && x <= 0b00_0011 => Err(Error::Reserved),
-        x if x >= 0b00_0100 && x <= 0b00_0111 => Err(Error::Reserved),
-        x if x >= 0b00_1000 && x <= 0b00_1100 => Ok(SubframeData::Fixed(read_fixed_subframe(
+        0b00_0010..=0b00_0011 => Err(reserved()),
+        0b00_0100..=0b00_0111 => Err(reserved()),
+        x @ 0b00_1000..=0b00_1100 => Ok(SubframeData::Fixed(read_fixed_subframe(
             reader,
             sample_depth,
             block_size,
-            wasted_bits,
             x & 0b111,
         )?)),
-        x if x >= 0b00_1101 && x <= 0b00_1111 => Err(Error::Reserved),
-        x if x >= 0b01_0000 && x <= 0b01_1111 => Err(Error::Reserved),
-        x if x >= 0b10_0000 && x <= 0b11_1111 => Ok(SubframeData::LPC(read_lpc_subframe(
+        0b00_1101..=0b00_1111 => Err(reserved()),
+        0b01_0000..=0b01_1111 => Err(reserved()),
+        x @ 0b10_0000..=0b11_1111 => Ok(SubframeData::LPC(read_lpc_subframe(
             reader,
             sample_depth,
             block_size,
-            wasted_bits,
-            dbg!(x & 0b01_1111 + 1),
+            (x & 0b01_1111) + 1,
         )?)),
         _ => unreachable!(),
     }?;
@@ -170,7 +360,7 @@ fn read_constant_subframe(
     sample_depth: u8,
 ) -> Result<ConstantSubframe, Error> {
     Ok(ConstantSubframe {
-        content: reader.read_signed(sample_depth)? as i32,
+        content: reader.read_signed(sample_depth)? as i64,
     })
 }
 
@@ -179,10 +369,11 @@ fn read_verbatim_subframe(
     sample_depth: u8,
     block_size: u32,
 ) -> Result<VerbatimSubframe, Error> {
-    let mut data = Vec::new();
+    // The frame header has checked the block size against the limits
+    let mut data = Vec::with_capacity(block_size as usize);
 
     for _ in 0..block_size {
-        data.push(reader.read_signed(sample_depth)? as i32);
+        data.push(reader.read_signed(sample_depth)? as i64);
     }
 
     Ok(VerbatimSubframe {
@@ -194,13 +385,12 @@ fn read_fixed_subframe(
     reader: &mut dyn BitstreamReader,
     sample_depth: u8,
     block_size: u32,
-    wasted_bits: u8,
     order: u8,
 ) -> Result<FixedSubframe, Error> {
     let mut warmup = Vec::new();
 
     for _ in 0..order {
-        warmup.push(reader.read_signed(sample_depth)? as i32)
+        warmup.push(reader.read_signed(sample_depth)? as i64)
     }
 
     let residual = read_residual(reader, block_size, order)?;
@@ -216,27 +406,34 @@ fn read_lpc_subframe(
     reader: &mut dyn BitstreamReader,
     sample_depth: u8,
     block_size: u32,
-    wasted_bits: u8,
     predictor_order: u8,
 ) -> Result<LPCSubframe, Error> {
     let mut warmup = Vec::new();
 
     for _ in 0..predictor_order {
-        warmup.push(reader.read_signed(sample_depth)? as i32)
+        warmup.push(reader.read_signed(sample_depth)? as i64)
     }
 
-    dbg!(&warmup);
-
     let coefficient_precision = reader.read_unsigned(4)? as u8 + 1;
     if coefficient_precision == 16 {
-        return Err(Error::Reserved)
+        return Err(Error::reserved(
+            Context::LPCPrecision,
+            Position::before(reader, 4),
+            0b1111,
+        ));
     }
 
     let shift = reader.read_signed(5)? as i8;
+    if shift < 0 {
+        return Err(Error::invalid(
+            Context::LPCShift,
+            Position::before(reader, 5),
+            shift as u8 as u64 & 0b1_1111,
+        ));
+    }
 
     let mut coefficients = Vec::new();
 
-    let required_shift = 16 - coefficient_precision;
     for _ in 0..predictor_order {
         let coefficient = reader.read_signed(coefficient_precision)? as i16;
         coefficients.push(coefficient);
@@ -244,36 +441,60 @@ fn read_lpc_subframe(
 
     let residual = read_residual(reader, block_size, predictor_order)?;
 
-    Err(Error::TooLong)
-//    Ok(LPCSubframe {
-//        order: predictor_order,
-//        warmup: warmup.into_boxed_slice(),
-//        coefficient_precision,
-//        shift,
-//        coefficients: coefficients.into_boxed_slice(),
-//        residual
-//    })
+    Ok(LPCSubframe {
+        order: predictor_order,
+        warmup: warmup.into_boxed_slice(),
+        coefficient_precision,
+        shift,
+        coefficients: coefficients.into_boxed_slice(),
+        residual,
+    })
 }
 
-fn read_residual(reader: &mut dyn BitstreamReader, block_size: u32, predictor_order: u8) -> Result<Residual, Error> {
+fn read_residual(
+    reader: &mut dyn BitstreamReader,
+    block_size: u32,
+    predictor_order: u8,
+) -> Result<Residual, Error> {
     let rice_type = reader.read_unsigned(2)? as u8;
     if rice_type >= 0b10 {
-        return Err(Error::Reserved);
+        return Err(Error::reserved(
+            Context::ResidualCodingMethod,
+            Position::before(reader, 2),
+            rice_type as u64,
+        ));
     }
     let parameter_size = 4 + rice_type;
     let partition_order = reader.read_unsigned(4)? as u8;
+    reader.report(&Event::Residual {
+        position: Position::before(reader, 6),
+        parameter_size,
+        partition_order,
+    });
+    // Every partition has the same size, the first one also holds the warmup samples
+    let partition_size = block_size >> partition_order;
+    if partition_size << partition_order != block_size || partition_size < predictor_order as u32 {
+        return Err(Error::invalid(
+            Context::PartitionOrder,
+            Position::before(reader, 4),
+            partition_order as u64,
+        ));
+    }
 
     let mut partitions = Vec::new();
 
     for idx in 0..(1u16 << partition_order as u16) {
-        partitions.push(read_rice_partition(
+        let partition_start = Position::of(reader);
+        let partition = read_rice_partition(
             reader,
             parameter_size,
             block_size,
             partition_order,
             predictor_order,
-            idx
-        )?);
+            idx,
+        )
+        .map_err(|e| e.within(Context::ResidualPartition(idx), partition_start))?;
+        partitions.push(partition);
     }
 
     Ok(Residual {
@@ -297,11 +518,22 @@ fn read_rice_partition(
         block_size >> partition_order as u32
     };
 
+    let position = Position::of(reader);
     let encoding_parameter = reader.read_unsigned(parameter_size)? as u8;
+    let escape_bits = if encoding_parameter == (1u8 << parameter_size) - 1 {
+        Some(reader.read_unsigned(5)? as u8)
+    } else {
+        None
+    };
+    reader.report(&Event::ResidualPartition {
+        position,
+        index: idx,
+        encoding_parameter,
+        escape_bits,
+    });
 
     let mut residual = Vec::new();
-    if encoding_parameter == 1u8 << parameter_size - 1 {
-        let residual_size = reader.read_unsigned(5)? as u8;
+    if let Some(residual_size) = escape_bits {
         // raw encoding
         for _ in 0..num_samples {
             residual.push(reader.read_signed(residual_size)? as i32);
@@ -314,6 +546,7 @@ fn read_rice_partition(
 
     Ok(RICEPartition {
         encoding_parameter,
-        residual: residual.into_boxed_slice()
+        escape_bits,
+        residual: residual.into_boxed_slice(),
     })
 }
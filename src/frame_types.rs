@@ -1,4 +1,9 @@
+use alloc::boxed::Box;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Frame {
     pub is_variable: bool,
     pub block_size: u32,
@@ -6,13 +11,31 @@ pub struct Frame {
     pub num_channels: u8,
     pub channel_assignment: ChannelAssignment,
     pub sample_depth: u8,
-    pub frame_or_sample_number: Option<u64>,
+    pub frame_or_sample_number: FrameOrSampleNumber,
     pub header_crc: u8,
     pub subframes: Box<[Subframe]>,
     pub overall_crc: u16,
 }
 
+/// Index of a frame in a fixed-blocksize stream, 31 bits used
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FrameNumber(pub u32);
+
+/// Index of the first sample of a frame in a variable-blocksize stream, 36 bits used
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SampleNumber(pub u64);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum FrameOrSampleNumber {
+    Frame(FrameNumber),
+    Sample(SampleNumber),
+}
+
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ChannelAssignment {
     Direct,
     LeftSide,
@@ -20,12 +43,25 @@ pub enum ChannelAssignment {
     MidSide,
 }
 
+impl ChannelAssignment {
+    /// Index of the channel holding the difference, which needs one extra bit of depth
+    pub fn side_channel(self) -> Option<usize> {
+        match self {
+            ChannelAssignment::Direct => None,
+            ChannelAssignment::LeftSide | ChannelAssignment::MidSide => Some(1),
+            ChannelAssignment::RightSide => Some(0),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Subframe {
     pub wasted_bits: u8,
     pub data: SubframeData,
 }
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum SubframeData {
     Constant(ConstantSubframe),
     Verbatim(VerbatimSubframe),
@@ -34,25 +70,51 @@ pub enum SubframeData {
     Reserved,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SubframeType {
+    Constant,
+    Verbatim,
+    Fixed { order: u8 },
+    LPC { order: u8 },
+}
+
+impl SubframeData {
+    /// The kind of subframe with its predictor order, None for a reserved type
+    pub fn subframe_type(&self) -> Option<SubframeType> {
+        match self {
+            SubframeData::Constant(_) => Some(SubframeType::Constant),
+            SubframeData::Verbatim(_) => Some(SubframeType::Verbatim),
+            SubframeData::Fixed(fixed) => Some(SubframeType::Fixed { order: fixed.order }),
+            SubframeData::LPC(lpc) => Some(SubframeType::LPC { order: lpc.order }),
+            SubframeData::Reserved => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct ConstantSubframe {
-    pub content: i32,
+    pub content: i64, // Up to 33 bits in the side channel of 32-bit audio
 }
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct VerbatimSubframe {
-    pub content: Box<[i32]>,
+    pub content: Box<[i64]>,
 }
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct FixedSubframe {
     pub order: u8,
-    pub warmup: Box<[i32]>,
+    pub warmup: Box<[i64]>,
     pub residual: Residual,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LPCSubframe {
     pub order: u8,
-    pub warmup: Box<[i32]>,
+    pub warmup: Box<[i64]>,
     pub coefficient_precision: u8,
     pub shift: i8, // Should be sign extended to i8
     pub coefficients: Box<[i16]>,
@@ -60,6 +122,7 @@ pub struct LPCSubframe {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Residual {
     pub parameter_size: u8, // RICE is 4, RICE2 = 5
     pub order: u8,
@@ -67,7 +130,9 @@ pub struct Residual {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct RICEPartition {
     pub encoding_parameter: u8,
-    pub residual: Box<[i32]>,
+    pub escape_bits: Option<u8>, // Bits per sample of an escaped partition
+    pub residual: Box<[i32]>,    // The format limits residuals to 32 bits, even for 32-bit audio
 }
@@ -1,7 +1,37 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod analysis;
+#[cfg(feature = "async")]
+pub mod async_decoder;
 pub mod bitstream;
 pub mod block_parser;
+#[cfg(feature = "std")]
+pub mod block_writer;
+pub(crate) mod crc;
+pub mod decoder;
+pub mod diagnostics;
 pub mod error;
+pub mod frame_index;
 pub mod frame_parser;
 pub mod frame_types;
+pub mod limits;
+pub(crate) mod lpc;
+#[cfg(feature = "std")]
+pub mod matroska_parser;
 pub mod metadata_types;
-pub(crate) mod rice;
\ No newline at end of file
+#[cfg(feature = "std")]
+pub mod mp4_parser;
+#[cfg(feature = "std")]
+pub mod ogg_writer;
+#[cfg(feature = "std")]
+pub mod parallel_decoder;
+pub mod position;
+pub mod push_decoder;
+pub(crate) mod rice;
+pub mod sample_decoder;
+#[cfg(feature = "std")]
+pub mod seek_table;
+#[cfg(feature = "serde")]
+pub(crate) mod serde_helpers;
@@ -0,0 +1,41 @@
+/// Bounds on what the parsers accept, so untrusted input fails with an error instead of making
+/// them allocate without limit
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Limits {
+    /// Total size of all metadata blocks in bytes
+    pub max_metadata_size: usize,
+    /// Size of a PICTURE block in bytes
+    pub max_picture_size: usize,
+    /// Samples per channel of a frame
+    pub max_block_size: u32,
+    /// Memory parsing a single metadata block, frame or container element may take, in bytes
+    ///
+    /// This bounds each item on its own rather than everything allocated at once. The memory
+    /// held at a time is at most `max_metadata_size` for the metadata plus this much for each
+    /// frame in flight: one for most decoders, a few per thread for a `ParallelDecoder`.
+    pub max_item_allocation: usize,
+}
+
+impl Limits {
+    /// Everything the format allows, except for the total size of the metadata
+    pub const DEFAULT: Limits = Limits {
+        max_metadata_size: 64 << 20,
+        max_picture_size: (1 << 24) - 1,
+        max_block_size: 65535,
+        max_item_allocation: 64 << 20,
+    };
+
+    /// Memory needed to parse and decode a frame of `block_size` samples in `num_channels`
+    /// channels, counting 8 bytes for each sample of a subframe and of the output
+    pub(crate) fn frame_allocation(block_size: u32, num_channels: u8) -> usize {
+        (block_size as usize)
+            .saturating_mul(num_channels as usize)
+            .saturating_mul(16)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::DEFAULT
+    }
+}
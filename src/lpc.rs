@@ -0,0 +1,393 @@
+const MAX_ORDER: usize = 32;
+
+/// Runs the prediction filter over `samples`, which start with one warmup sample per coefficient
+/// followed by the residual. `coefficients[0]` applies to the most recent sample. Predictions
+/// are summed in 64 bits if `wide` is set, and in 32 bits otherwise.
+pub(crate) fn restore(samples: &mut [i32], coefficients: &[i32], shift: u32, wide: bool) {
+    debug_assert!(coefficients.len() <= MAX_ORDER && coefficients.len() <= samples.len());
+    if coefficients.is_empty() {
+        return;
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if x86::has_avx2() {
+            return unsafe { x86::restore_avx2(samples, coefficients, shift, wide) };
+        }
+        if x86::has_sse41() {
+            return unsafe { x86::restore_sse41(samples, coefficients, shift, wide) };
+        }
+    }
+    restore_portable(samples, coefficients, shift, wide)
+}
+
+/// Whether predictions for samples of `sample_depth` bits can overflow 32 bits
+pub(crate) fn needs_wide(coefficients: &[i32], sample_depth: u8) -> bool {
+    let sum: u64 = coefficients.iter().map(|c| c.unsigned_abs() as u64).sum();
+    sum << sample_depth.saturating_sub(1) > i32::MAX as u64
+}
+
+/// The plain version of the filter, which the others have to match exactly
+pub(crate) fn restore_scalar(samples: &mut [i32], coefficients: &[i32], shift: u32, wide: bool) {
+    let order = coefficients.len();
+    for idx in order..samples.len() {
+        let history = samples[idx - order..idx].iter().rev();
+        let prediction = if wide {
+            let prediction = coefficients
+                .iter()
+                .zip(history)
+                .fold(0i64, |sum, (c, s)| sum.wrapping_add(*c as i64 * *s as i64));
+            (prediction >> shift) as i32
+        } else {
+            let prediction = coefficients
+                .iter()
+                .zip(history)
+                .fold(0i32, |sum, (c, s)| sum.wrapping_add(c.wrapping_mul(*s)));
+            prediction >> shift
+        };
+        samples[idx] = samples[idx].wrapping_add(prediction);
+    }
+}
+
+/// The filter over samples of more than 32 bits, which only the side channel of 32-bit audio has
+pub(crate) fn restore_wide_samples(samples: &mut [i64], coefficients: &[i32], shift: u32) {
+    let order = coefficients.len();
+    for idx in order..samples.len() {
+        let history = samples[idx - order..idx].iter().rev();
+        let prediction = coefficients.iter().zip(history).fold(0i64, |sum, (c, s)| {
+            sum.wrapping_add((*c as i64).wrapping_mul(*s))
+        });
+        samples[idx] = samples[idx].wrapping_add(prediction >> shift);
+    }
+}
+
+/// Sums the taps in fixed groups of lanes, which the compiler vectorizes for targets without
+/// a hand written version, such as NEON
+pub(crate) fn restore_portable(samples: &mut [i32], coefficients: &[i32], shift: u32, wide: bool) {
+    const LANES: usize = 4;
+    let order = coefficients.len();
+    let (reversed, padded) = reverse_padded(coefficients, LANES);
+    let end = vector_end(samples.len(), order, padded);
+
+    for idx in order..end {
+        let history = samples[idx - order..idx - order + padded].chunks_exact(LANES);
+        let taps = reversed[..padded].chunks_exact(LANES);
+        let prediction = if wide {
+            let mut sums = [0i64; LANES];
+            for (history, taps) in history.zip(taps) {
+                for lane in 0..LANES {
+                    let product = taps[lane] as i64 * history[lane] as i64;
+                    sums[lane] = sums[lane].wrapping_add(product);
+                }
+            }
+            (sums.iter().fold(0i64, |sum, s| sum.wrapping_add(*s)) >> shift) as i32
+        } else {
+            let mut sums = [0i32; LANES];
+            for (history, taps) in history.zip(taps) {
+                for lane in 0..LANES {
+                    let product = taps[lane].wrapping_mul(history[lane]);
+                    sums[lane] = sums[lane].wrapping_add(product);
+                }
+            }
+            sums.iter().fold(0i32, |sum, s| sum.wrapping_add(*s)) >> shift
+        };
+        samples[idx] = samples[idx].wrapping_add(prediction);
+    }
+    restore_scalar(&mut samples[end - order..], coefficients, shift, wide);
+}
+
+/// The coefficients in the order of the samples they apply to, oldest first, followed by zeros
+/// up to a whole number of `lanes`
+fn reverse_padded(coefficients: &[i32], lanes: usize) -> ([i32; MAX_ORDER], usize) {
+    let mut reversed = [0; MAX_ORDER];
+    for (reversed, coefficient) in reversed.iter_mut().zip(coefficients.iter().rev()) {
+        *reversed = *coefficient;
+    }
+    (reversed, coefficients.len().div_ceil(lanes) * lanes)
+}
+
+/// End of the samples whose history, padded to `padded` taps, does not run past the end.
+/// The padding covers the sample itself and the ones after it, with zero coefficients.
+fn vector_end(num_samples: usize, order: usize, padded: usize) -> usize {
+    (num_samples + order).saturating_sub(padded).max(order)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86 {
+    use super::{restore_scalar, reverse_padded, vector_end, MAX_ORDER};
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::*;
+
+    #[cfg(feature = "std")]
+    pub(super) fn has_avx2() -> bool {
+        is_x86_feature_detected!("avx2")
+    }
+
+    #[cfg(feature = "std")]
+    pub(super) fn has_sse41() -> bool {
+        is_x86_feature_detected!("sse4.1")
+    }
+
+    // Without std only what the target is built for can be used
+    #[cfg(not(feature = "std"))]
+    pub(super) fn has_avx2() -> bool {
+        cfg!(target_feature = "avx2")
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub(super) fn has_sse41() -> bool {
+        cfg!(target_feature = "sse4.1")
+    }
+
+    /// Eight taps at a time in 32 bits, four in 64 bits
+    #[target_feature(enable = "avx2")]
+    pub(super) unsafe fn restore_avx2(
+        samples: &mut [i32],
+        coefficients: &[i32],
+        shift: u32,
+        wide: bool,
+    ) {
+        let order = coefficients.len();
+        let lanes = if wide { 4 } else { 8 };
+        let (reversed, padded) = reverse_padded(coefficients, lanes);
+        let end = vector_end(samples.len(), order, padded);
+
+        let mut taps = [_mm256_setzero_si256(); MAX_ORDER / 4];
+        for (idx, taps) in taps[..padded / lanes].iter_mut().enumerate() {
+            let reversed = reversed.as_ptr().add(idx * lanes) as *const __m128i;
+            *taps = if wide {
+                _mm256_cvtepi32_epi64(_mm_loadu_si128(reversed))
+            } else {
+                _mm256_loadu_si256(reversed as *const __m256i)
+            };
+        }
+        let taps = &taps[..padded / lanes];
+
+        let samples_ptr = samples.as_mut_ptr();
+        for idx in order..end {
+            let history = samples_ptr.add(idx - order);
+            let prediction = if wide {
+                let mut sum = _mm256_setzero_si256();
+                for (group, taps) in taps.iter().enumerate() {
+                    let history = _mm_loadu_si128(history.add(4 * group) as *const __m128i);
+                    let product = _mm256_mul_epi32(_mm256_cvtepi32_epi64(history), *taps);
+                    sum = _mm256_add_epi64(sum, product);
+                }
+                let sum = _mm_add_epi64(
+                    _mm256_castsi256_si128(sum),
+                    _mm256_extracti128_si256::<1>(sum),
+                );
+                (sum_epi64(sum) >> shift) as i32
+            } else {
+                let mut sum = _mm256_setzero_si256();
+                for (group, taps) in taps.iter().enumerate() {
+                    let history = _mm256_loadu_si256(history.add(8 * group) as *const __m256i);
+                    sum = _mm256_add_epi32(sum, _mm256_mullo_epi32(history, *taps));
+                }
+                let sum = _mm_add_epi32(
+                    _mm256_castsi256_si128(sum),
+                    _mm256_extracti128_si256::<1>(sum),
+                );
+                sum_epi32(sum) >> shift
+            };
+            *samples_ptr.add(idx) = (*samples_ptr.add(idx)).wrapping_add(prediction);
+        }
+        restore_scalar(&mut samples[end - order..], coefficients, shift, wide);
+    }
+
+    /// Four taps at a time in 32 bits, two in 64 bits
+    #[target_feature(enable = "sse4.1")]
+    pub(super) unsafe fn restore_sse41(
+        samples: &mut [i32],
+        coefficients: &[i32],
+        shift: u32,
+        wide: bool,
+    ) {
+        let order = coefficients.len();
+        let lanes = if wide { 2 } else { 4 };
+        let (reversed, padded) = reverse_padded(coefficients, lanes);
+        let end = vector_end(samples.len(), order, padded);
+
+        let mut taps = [_mm_setzero_si128(); MAX_ORDER / 2];
+        for (idx, taps) in taps[..padded / lanes].iter_mut().enumerate() {
+            let reversed = reversed.as_ptr().add(idx * lanes) as *const __m128i;
+            *taps = if wide {
+                _mm_cvtepi32_epi64(_mm_loadl_epi64(reversed))
+            } else {
+                _mm_loadu_si128(reversed)
+            };
+        }
+        let taps = &taps[..padded / lanes];
+
+        let samples_ptr = samples.as_mut_ptr();
+        for idx in order..end {
+            let history = samples_ptr.add(idx - order);
+            let prediction = if wide {
+                let mut sum = _mm_setzero_si128();
+                for (group, taps) in taps.iter().enumerate() {
+                    let history = _mm_loadl_epi64(history.add(2 * group) as *const __m128i);
+                    let product = _mm_mul_epi32(_mm_cvtepi32_epi64(history), *taps);
+                    sum = _mm_add_epi64(sum, product);
+                }
+                (sum_epi64(sum) >> shift) as i32
+            } else {
+                let mut sum = _mm_setzero_si128();
+                for (group, taps) in taps.iter().enumerate() {
+                    let history = _mm_loadu_si128(history.add(4 * group) as *const __m128i);
+                    sum = _mm_add_epi32(sum, _mm_mullo_epi32(history, *taps));
+                }
+                sum_epi32(sum) >> shift
+            };
+            *samples_ptr.add(idx) = (*samples_ptr.add(idx)).wrapping_add(prediction);
+        }
+        restore_scalar(&mut samples[end - order..], coefficients, shift, wide);
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn sum_epi32(sum: __m128i) -> i32 {
+        let sum = _mm_add_epi32(sum, _mm_shuffle_epi32::<0b01_00_11_10>(sum));
+        let sum = _mm_add_epi32(sum, _mm_shuffle_epi32::<0b10_11_00_01>(sum));
+        _mm_cvtsi128_si32(sum)
+    }
+
+    #[target_feature(enable = "sse4.1")]
+    unsafe fn sum_epi64(sum: __m128i) -> i64 {
+        let mut lanes = [0i64; 2];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, sum);
+        lanes[0].wrapping_add(lanes[1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    /// xorshift64, enough to get varied inputs without a dependency
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn signed(&mut self, bits: u32) -> i32 {
+            ((self.next() as i64) >> (64 - bits)) as i32
+        }
+    }
+
+    type Restore = fn(&mut [i32], &[i32], u32, bool);
+
+    fn implementations() -> Vec<(&'static str, Restore)> {
+        let mut implementations: Vec<(&'static str, Restore)> =
+            vec![("portable", restore_portable), ("dispatch", restore)];
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if x86::has_sse41() {
+                implementations.push(("sse4.1", |s, c, shift, wide| unsafe {
+                    x86::restore_sse41(s, c, shift, wide)
+                }));
+            }
+            if x86::has_avx2() {
+                implementations.push(("avx2", |s, c, shift, wide| unsafe {
+                    x86::restore_avx2(s, c, shift, wide)
+                }));
+            }
+        }
+        implementations
+    }
+
+    fn check_against_scalar(samples: &[i32], coefficients: &[i32], shift: u32, wide: bool) {
+        let mut expected = samples.to_vec();
+        restore_scalar(&mut expected, coefficients, shift, wide);
+        for (name, restore) in implementations() {
+            let mut actual = samples.to_vec();
+            restore(&mut actual, coefficients, shift, wide);
+            assert_eq!(
+                actual,
+                expected,
+                "{} differs with order {}, shift {}, wide {}, {} samples",
+                name,
+                coefficients.len(),
+                shift,
+                wide,
+                samples.len()
+            );
+        }
+    }
+
+    #[test]
+    fn scalar_inverts_prediction() {
+        let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+        let coefficients = [1468, -1020, 544, -231, 88];
+        let shift = 10;
+        let signal: Vec<i32> = (0..512).map(|_| rng.signed(16)).collect();
+
+        let order = coefficients.len();
+        let mut encoded = signal.clone();
+        for idx in order..signal.len() {
+            let prediction: i64 = coefficients
+                .iter()
+                .zip(signal[idx - order..idx].iter().rev())
+                .map(|(c, s)| *c as i64 * *s as i64)
+                .sum();
+            encoded[idx] = signal[idx] - (prediction >> shift) as i32;
+        }
+
+        for wide in [false, true] {
+            let mut decoded = encoded.clone();
+            restore_scalar(&mut decoded, &coefficients, shift, wide);
+            assert_eq!(decoded, signal);
+        }
+    }
+
+    #[test]
+    fn matches_scalar_for_lpc() {
+        let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+        for order in 1..=MAX_ORDER {
+            for &(depth, precision) in &[(16, 12), (24, 15), (25, 15), (32, 15), (33, 15)] {
+                let coefficients: Vec<i32> = (0..order).map(|_| rng.signed(precision)).collect();
+                for &length in &[order, order + 1, order + 7, 4096] {
+                    let samples: Vec<i32> =
+                        (0..length).map(|_| rng.signed(depth.min(32))).collect();
+                    let shift = (rng.next() % 16) as u32;
+                    let wide = needs_wide(&coefficients, depth as u8);
+                    check_against_scalar(&samples, &coefficients, shift, wide);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn matches_scalar_when_wrapping() {
+        // Damaged streams can hold anything, every version has to wrap around the same way
+        let mut rng = Rng(0xdead_beef_cafe_f00d);
+        for order in 1..=MAX_ORDER {
+            let coefficients: Vec<i32> = (0..order).map(|_| rng.signed(32)).collect();
+            let samples: Vec<i32> = (0..300).map(|_| rng.signed(32)).collect();
+            for wide in [false, true] {
+                check_against_scalar(&samples, &coefficients, 31, wide);
+                check_against_scalar(&samples, &coefficients, 0, wide);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_scalar_for_fixed() {
+        let fixed: [&[i32]; 4] = [&[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+        let mut rng = Rng(0x0123_4567_89ab_cdef);
+        for coefficients in fixed.iter() {
+            for &depth in &[8, 16, 24, 32] {
+                let samples: Vec<i32> = (0..1000).map(|_| rng.signed(depth)).collect();
+                let wide = needs_wide(coefficients, depth as u8);
+                check_against_scalar(&samples, coefficients, 0, wide);
+            }
+        }
+    }
+}
@@ -1,46 +1,518 @@
-use muflac::bitstream::BufferedBitstreamReader;
-use muflac::block_parser::{read_magic, read_metadata_block};
+use muflac::analysis::{analyze_frame, FrameAnalysis};
+use muflac::bitstream::{BitstreamReader, BufferedBitstreamReader};
+use muflac::block_parser::{read_magic, read_nth_metadata_block};
 use muflac::error::Error;
-use muflac::frame_parser::read_frame;
-use muflac::metadata_types::{MetadataBlock, MetadataBlockData};
+use muflac::frame_index::FrameIndex;
+use muflac::frame_types::SubframeType;
+use muflac::metadata_types::{
+    MetadataBlock, MetadataBlockCueSheet, MetadataBlockData, MetadataBlockPicture,
+    MetadataBlockStreamInfo, MetadataBlockVorbisComment,
+};
 use std::env::args_os;
-use std::fs::File;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
 use std::path::Path;
+use std::process::exit;
+
+const BLOCK_TYPES: [&str; 7] = [
+    "STREAMINFO",
+    "PADDING",
+    "APPLICATION",
+    "SEEKTABLE",
+    "VORBIS_COMMENT",
+    "CUESHEET",
+    "PICTURE",
+];
+
+/// A metadata block with where it was found, offset and length in bytes
+struct BlockInfo {
+    offset: usize,
+    length: usize,
+    block: MetadataBlock,
+}
 
 fn main() {
-    let mut args_iter = args_os();
-    args_iter.next();
-    let filename = args_iter.next().unwrap_or("test.flac".into());
-    let file = Path::new(&filename);
-    let mut stream = BufferedBitstreamReader::<File>::open(file)
-        .unwrap_or_else(|e| panic!("Unable to create reader {:?}", e));
+    let args: Vec<OsString> = args_os().skip(1).collect();
+    let result = match args.first().and_then(|arg| arg.to_str()) {
+        Some("analyze") => analyze(&args[1..]),
+        Some("info") => info(&args[1..]),
+        _ => info(&args),
+    };
 
-    let magic_result = read_magic(&mut stream);
-    println!("magic: {:?}", magic_result);
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        let mut source = std::error::Error::source(&e);
+        while let Some(err) = source {
+            eprintln!("  caused by: {}", err);
+            source = err.source();
+        }
+        exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!(
+        "usage: muflac [info] [--json] [--block-type=TYPE,...] [--except-block-type=TYPE,...] FILE"
+    );
+    eprintln!("       muflac analyze [--csv] FILE");
+    exit(2);
+}
 
-    let block = &read_metadata_block(&mut stream);
-    println!("streaminfo block: {:?}", block);
-    let stream_info = match &block.as_ref().unwrap().content {
-        MetadataBlockData::StreamInfo(si) => Some(si),
-        _ => None,
+/// Prints how the bits of every frame and subframe are spent
+fn analyze(args: &[OsString]) -> Result<(), Error> {
+    let mut csv = false;
+    let mut filename = None;
+    for arg in args {
+        match arg.to_str() {
+            Some("--csv") => csv = true,
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
     }
-    .unwrap();
+    let filename = filename.unwrap_or_else(|| usage());
 
-    let mut finished = is_last(block);
-    while !finished {
-        let block = &read_metadata_block(&mut stream);
-        println!("block: {:?}", block);
-        finished = is_last(block);
+    let mut index = FrameIndex::open(Path::new(filename))?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if csv {
+        writeln!(
+            out,
+            "frame,offset,size,first_sample,block_size,channel_assignment,frame_header_bits,\
+             subframe,type,order,sample_depth,wasted_bits,lpc_precision,lpc_shift,header_bits,\
+             warmup_bits,coefficient_bits,residual_bits,partition_order,escaped_partitions,\
+             rice_parameters"
+        )?;
     }
 
-    let frame = read_frame(&mut stream, stream_info);
-    println!("first frame: {:?}", frame)
+    let mut frame_idx = 0;
+    while let Some((entry, frame)) = index.next_frame()? {
+        let analysis = analyze_frame(&entry, &frame);
+        if csv {
+            write_csv(&mut out, frame_idx, &analysis)?;
+        } else {
+            write_text(&mut out, frame_idx, &analysis)?;
+        }
+        frame_idx += 1;
+    }
+    Ok(())
+}
+
+fn type_name(subframe_type: Option<SubframeType>) -> (&'static str, u8) {
+    match subframe_type {
+        Some(SubframeType::Constant) => ("CONSTANT", 0),
+        Some(SubframeType::Verbatim) => ("VERBATIM", 0),
+        Some(SubframeType::Fixed { order }) => ("FIXED", order),
+        Some(SubframeType::LPC { order }) => ("LPC", order),
+        None => ("RESERVED", 0),
+    }
 }
 
-fn is_last(block: &Result<MetadataBlock, Error>) -> bool {
-    if let Ok(blk) = block.as_ref() {
-        blk.is_last
+fn write_text(out: &mut dyn Write, frame_idx: u64, analysis: &FrameAnalysis) -> io::Result<()> {
+    writeln!(
+        out,
+        "frame={}\toffset={}\tbits={}\tfirst_sample={}\tblocksize={}\tchannel_assignment={:?}\theader_bits={}\tfooter_bits={}",
+        frame_idx,
+        analysis.offset,
+        8 * analysis.size,
+        analysis.first_sample,
+        analysis.block_size,
+        analysis.channel_assignment,
+        analysis.header_bits,
+        analysis.footer_bits,
+    )?;
+
+    for (channel, subframe) in analysis.subframes.iter().enumerate() {
+        let (name, order) = type_name(subframe.subframe_type);
+        write!(
+            out,
+            "\tsubframe={}\ttype={}\torder={}\tsample_depth={}\twasted_bits={}",
+            channel, name, order, subframe.sample_depth, subframe.wasted_bits
+        )?;
+        if let (Some(precision), Some(shift)) = (subframe.lpc_precision, subframe.lpc_shift) {
+            write!(
+                out,
+                "\tqlp_coeff_precision={}\tquantization_level={}",
+                precision, shift
+            )?;
+        }
+        writeln!(
+            out,
+            "\theader_bits={}\twarmup_bits={}\tcoefficient_bits={}\tresidual_bits={}",
+            subframe.header_bits,
+            subframe.warmup_bits,
+            subframe.coefficient_bits,
+            subframe.residual_bits
+        )?;
+
+        for (idx, partition) in subframe.partitions.iter().enumerate() {
+            match partition.escape_bits {
+                Some(escape_bits) => writeln!(
+                    out,
+                    "\t\tpartition={}\tescaped\traw_bits={}\tsamples={}\tbits={}",
+                    idx, escape_bits, partition.num_samples, partition.bits
+                )?,
+                None => writeln!(
+                    out,
+                    "\t\tpartition={}\tparameter={}\tsamples={}\tbits={}",
+                    idx, partition.rice_parameter, partition.num_samples, partition.bits
+                )?,
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_csv(out: &mut dyn Write, frame_idx: u64, analysis: &FrameAnalysis) -> io::Result<()> {
+    for (channel, subframe) in analysis.subframes.iter().enumerate() {
+        let (name, order) = type_name(subframe.subframe_type);
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        let partition_order = match subframe.partitions.len() {
+            0 => String::new(),
+            n => n.trailing_zeros().to_string(),
+        };
+        let escaped = subframe
+            .partitions
+            .iter()
+            .filter(|p| p.escape_bits.is_some())
+            .count();
+        let parameters: Vec<String> = subframe
+            .partitions
+            .iter()
+            .map(|p| match p.escape_bits {
+                Some(escape_bits) => format!("e{}", escape_bits),
+                None => p.rice_parameter.to_string(),
+            })
+            .collect();
+
+        writeln!(
+            out,
+            "{},{},{},{},{},{:?},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            frame_idx,
+            analysis.offset,
+            analysis.size,
+            analysis.first_sample,
+            analysis.block_size,
+            analysis.channel_assignment,
+            analysis.header_bits,
+            channel,
+            name,
+            order,
+            subframe.sample_depth,
+            subframe.wasted_bits,
+            optional(subframe.lpc_precision.map(|p| p.to_string())),
+            optional(subframe.lpc_shift.map(|s| s.to_string())),
+            subframe.header_bits,
+            subframe.warmup_bits,
+            subframe.coefficient_bits,
+            subframe.residual_bits,
+            partition_order,
+            escaped,
+            parameters.join(" "),
+        )?;
+    }
+    Ok(())
+}
+
+/// Lists the metadata blocks like `metaflac --list`, the types to show are given by name or number
+fn info(args: &[OsString]) -> Result<(), Error> {
+    let mut json = false;
+    let mut block_types: Option<Vec<u8>> = None;
+    let mut except_block_types = Vec::new();
+    let mut filename = None;
+    for arg in args {
+        match arg.to_str() {
+            Some("--json") if cfg!(feature = "json") => json = true,
+            Some("--json") => {
+                eprintln!("error: JSON output needs the json feature");
+                exit(2);
+            }
+            Some(arg) if arg.starts_with("--block-type=") => {
+                block_types = Some(parse_block_types(&arg["--block-type=".len()..]))
+            }
+            Some(arg) if arg.starts_with("--except-block-type=") => {
+                except_block_types = parse_block_types(&arg["--except-block-type=".len()..])
+            }
+            _ if filename.is_none() => filename = Some(arg),
+            _ => usage(),
+        }
+    }
+    let filename = Path::new(filename.unwrap_or_else(|| usage()));
+
+    let file_size = fs::metadata(filename)?.len() as usize;
+    let mut reader = BufferedBitstreamReader::open(filename)?;
+    read_magic(&mut reader)?;
+    // Blocks before a damaged one are still printed
+    let mut blocks = Vec::new();
+    let mut metadata_size = 0;
+    let result = loop {
+        let offset = reader.get_total_position() / 8;
+        match read_nth_metadata_block(&mut reader, blocks.len(), &mut metadata_size) {
+            Ok(block) => {
+                let is_last = block.is_last;
+                let length = reader.get_total_position() / 8 - offset - 4;
+                blocks.push(BlockInfo {
+                    offset,
+                    length,
+                    block,
+                });
+                if is_last {
+                    break Ok(());
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    let audio_size = file_size.saturating_sub(reader.get_total_position() / 8);
+
+    let selected: Vec<(usize, &BlockInfo)> = blocks
+        .iter()
+        .enumerate()
+        .filter(|(_, info)| {
+            let block_type = info.block.content.block_type();
+            block_types.as_ref().is_none_or(|t| t.contains(&block_type))
+                && !except_block_types.contains(&block_type)
+        })
+        .collect();
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if json {
+        #[cfg(feature = "json")]
+        write_json(&mut out, &selected)?;
     } else {
-        true
+        for (idx, info) in selected {
+            write_block(&mut out, idx, info, audio_size)?;
+        }
     }
+    result
+}
+
+fn parse_block_types(list: &str) -> Vec<u8> {
+    list.split(',')
+        .map(|name| {
+            BLOCK_TYPES
+                .iter()
+                .position(|t| t.eq_ignore_ascii_case(name))
+                .map(|t| t as u8)
+                .or_else(|| name.parse().ok().filter(|t| *t < 128))
+                .unwrap_or_else(|| usage())
+        })
+        .collect()
+}
+
+fn block_type_name(block_type: u8) -> &'static str {
+    match BLOCK_TYPES.get(block_type as usize) {
+        Some(name) => name,
+        None if block_type == 127 => "INVALID",
+        None => "RESERVED",
+    }
+}
+
+fn write_block(
+    out: &mut dyn Write,
+    idx: usize,
+    info: &BlockInfo,
+    audio_size: usize,
+) -> io::Result<()> {
+    let block_type = info.block.content.block_type();
+    writeln!(out, "METADATA block #{}", idx)?;
+    writeln!(
+        out,
+        "  type: {} ({})",
+        block_type,
+        block_type_name(block_type)
+    )?;
+    writeln!(out, "  is last: {}", info.block.is_last)?;
+    writeln!(out, "  offset: {}", info.offset)?;
+    writeln!(out, "  length: {}", info.length)?;
+
+    match &info.block.content {
+        MetadataBlockData::StreamInfo(stream_info) => {
+            write_stream_info(out, stream_info, audio_size)?
+        }
+        MetadataBlockData::Application(data) => {
+            let id = data.get(..4).unwrap_or(data);
+            let hex: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+            writeln!(out, "  application ID: {} \"{}\"", hex, text(id))?;
+            writeln!(out, "  data length: {}", data.len().saturating_sub(4))?;
+        }
+        MetadataBlockData::SeekTable(seek_table) => {
+            writeln!(out, "  seek points: {}", seek_table.seek_points.len())?;
+            for (idx, point) in seek_table.seek_points.iter().enumerate() {
+                if point.sample_number == u64::MAX {
+                    writeln!(out, "    point {}: PLACEHOLDER", idx)?;
+                } else {
+                    writeln!(
+                        out,
+                        "    point {}: sample_number={}, stream_offset={}, frame_samples={}",
+                        idx, point.sample_number, point.frame_offset, point.num_samples
+                    )?;
+                }
+            }
+        }
+        MetadataBlockData::VorbisComment(vorbis_comment) => {
+            write_vorbis_comment(out, vorbis_comment)?
+        }
+        MetadataBlockData::CueSheet(cue_sheet) => write_cue_sheet(out, cue_sheet)?,
+        MetadataBlockData::Picture(picture) => write_picture(out, picture)?,
+        MetadataBlockData::Padding(_)
+        | MetadataBlockData::Reserved(_)
+        | MetadataBlockData::Invalid => {}
+    }
+    Ok(())
+}
+
+fn write_stream_info(
+    out: &mut dyn Write,
+    stream_info: &MetadataBlockStreamInfo,
+    audio_size: usize,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "  minimum blocksize: {} samples",
+        stream_info.min_block_size
+    )?;
+    writeln!(
+        out,
+        "  maximum blocksize: {} samples",
+        stream_info.max_block_size
+    )?;
+    writeln!(
+        out,
+        "  minimum framesize: {} bytes",
+        stream_info.min_frame_size
+    )?;
+    writeln!(
+        out,
+        "  maximum framesize: {} bytes",
+        stream_info.max_frame_size
+    )?;
+    writeln!(out, "  sample rate: {} Hz", stream_info.sample_rate)?;
+    writeln!(out, "  channels: {}", stream_info.num_channels)?;
+    writeln!(out, "  bits per sample: {}", stream_info.sample_depth)?;
+    writeln!(out, "  total samples: {}", stream_info.num_samples)?;
+    // Both are unknown without the number of samples
+    if stream_info.sample_rate > 0 && stream_info.num_samples > 0 {
+        let duration = stream_info.num_samples as f64 / stream_info.sample_rate as f64;
+        writeln!(out, "  duration: {:.3} s", duration)?;
+        writeln!(
+            out,
+            "  average bitrate: {:.1} kbit/s",
+            8.0 * audio_size as f64 / duration / 1000.0
+        )?;
+    }
+    writeln!(
+        out,
+        "  MD5 signature: {:032x}",
+        stream_info.decoded_checksum
+    )
+}
+
+fn write_vorbis_comment(
+    out: &mut dyn Write,
+    vorbis_comment: &MetadataBlockVorbisComment,
+) -> io::Result<()> {
+    writeln!(
+        out,
+        "  vendor string: {}",
+        String::from_utf8_lossy(&vorbis_comment.vendor)
+    )?;
+    writeln!(out, "  comments: {}", vorbis_comment.comments.len())?;
+    for (idx, comment) in vorbis_comment.comments.iter().enumerate() {
+        writeln!(
+            out,
+            "    comment[{}]: {}",
+            idx,
+            String::from_utf8_lossy(comment)
+        )?;
+    }
+    Ok(())
+}
+
+fn write_cue_sheet(out: &mut dyn Write, cue_sheet: &MetadataBlockCueSheet) -> io::Result<()> {
+    writeln!(
+        out,
+        "  media catalog number: {}",
+        text(&cue_sheet.catalog_number)
+    )?;
+    writeln!(out, "  lead-in: {}", cue_sheet.num_lead_in_samples)?;
+    writeln!(out, "  is CD: {}", cue_sheet.is_cd)?;
+    writeln!(out, "  number of tracks: {}", cue_sheet.tracks.len())?;
+    for (idx, track) in cue_sheet.tracks.iter().enumerate() {
+        writeln!(out, "    track[{}]", idx)?;
+        writeln!(out, "      offset: {}", track.track_offset)?;
+        writeln!(out, "      number: {}", track.track_num)?;
+        writeln!(out, "      ISRC: {}", text(&track.track_isrc))?;
+        let track_type = if track.track_type {
+            "NON-AUDIO"
+        } else {
+            "AUDIO"
+        };
+        writeln!(out, "      type: {}", track_type)?;
+        writeln!(out, "      pre-emphasis: {}", track.pre_emphasis)?;
+        writeln!(out, "      number of index points: {}", track.indices.len())?;
+        for (idx, index) in track.indices.iter().enumerate() {
+            writeln!(out, "        index[{}]", idx)?;
+            writeln!(out, "          offset: {}", index.offset)?;
+            writeln!(out, "          number: {}", index.index_point)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_picture(out: &mut dyn Write, picture: &MetadataBlockPicture) -> io::Result<()> {
+    writeln!(
+        out,
+        "  picture type: {} ({:?})",
+        picture.picture_type.code(),
+        picture.picture_type
+    )?;
+    writeln!(out, "  MIME type: {}", text(&picture.mime_type))?;
+    writeln!(out, "  description: {}", text(&picture.description))?;
+    writeln!(out, "  width: {}", picture.width)?;
+    writeln!(out, "  height: {}", picture.height)?;
+    writeln!(out, "  depth: {}", picture.depth)?;
+    writeln!(out, "  colors: {}", picture.num_colors_used)?;
+    writeln!(out, "  data length: {}", picture.picture.len())
+}
+
+/// Text fields of the metadata are UTF-8 or ASCII, padded with NUL characters
+fn text(bytes: &[u8]) -> String {
+    let length = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    String::from_utf8_lossy(&bytes[..length]).into_owned()
+}
+
+/// Prints the blocks as a JSON array, with only the length of picture data
+#[cfg(feature = "json")]
+fn write_json(out: &mut dyn Write, blocks: &[(usize, &BlockInfo)]) -> io::Result<()> {
+    use serde_json::json;
+
+    let values: Vec<serde_json::Value> = blocks
+        .iter()
+        .map(|(idx, info)| {
+            let block_type = info.block.content.block_type();
+            let content = match &info.block.content {
+                MetadataBlockData::Picture(picture) => {
+                    let mut content = json!(&info.block.content);
+                    let summary = content["Picture"].as_object_mut().unwrap();
+                    summary.remove("picture");
+                    summary.insert("data_length".into(), json!(picture.picture.len()));
+                    content
+                }
+                content => json!(content),
+            };
+            json!({
+                "index": idx,
+                "type": block_type,
+                "type_name": block_type_name(block_type),
+                "is_last": info.block.is_last,
+                "offset": info.offset,
+                "length": info.length,
+                "content": content,
+            })
+        })
+        .collect();
+    serde_json::to_writer_pretty(&mut *out, &values)?;
+    writeln!(out)
 }
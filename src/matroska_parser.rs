@@ -0,0 +1,633 @@
+use crate::bitstream::BufferedBitstreamReader;
+use crate::block_parser::{read_magic, read_metadata_blocks};
+use crate::error::{Context, Error, Position};
+use crate::frame_parser::read_frame;
+use crate::frame_types::Frame;
+use crate::limits::Limits;
+use crate::metadata_types::{MetadataBlock, MetadataBlockStreamInfo};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::time::Duration;
+
+const ID_EBML: u32 = 0x1A45_DFA3;
+const ID_SEGMENT: u32 = 0x1853_8067;
+const ID_INFO: u32 = 0x1549_A966;
+const ID_TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const ID_TRACKS: u32 = 0x1654_AE6B;
+const ID_TRACK_ENTRY: u32 = 0xAE;
+const ID_TRACK_NUMBER: u32 = 0xD7;
+const ID_CODEC_ID: u32 = 0x86;
+const ID_CODEC_PRIVATE: u32 = 0x63A2;
+const ID_CLUSTER: u32 = 0x1F43_B675;
+const ID_TIMESTAMP: u32 = 0xE7;
+const ID_SIMPLE_BLOCK: u32 = 0xA3;
+const ID_BLOCK_GROUP: u32 = 0xA0;
+const ID_BLOCK: u32 = 0xA1;
+
+/// Master elements we descend into, everything else is either read or skipped whole
+const CONTAINERS: [u32; 6] = [
+    ID_SEGMENT,
+    ID_INFO,
+    ID_TRACKS,
+    ID_TRACK_ENTRY,
+    ID_CLUSTER,
+    ID_BLOCK_GROUP,
+];
+
+#[derive(Debug, Clone)]
+pub struct MatroskaFlacTrack {
+    pub track_number: u64,
+    pub stream_info: MetadataBlockStreamInfo,
+    pub metadata: Box<[MetadataBlock]>, // Including STREAMINFO, as stored in CodecPrivate
+}
+
+#[derive(Debug, Clone)]
+pub struct MatroskaFrame {
+    pub track_number: u64,
+    pub timestamp: Duration,
+    pub frame: Frame,
+}
+
+#[derive(Default)]
+struct TrackEntry {
+    track_number: u64,
+    codec_id: Box<[u8]>,
+    codec_private: Box<[u8]>,
+}
+
+struct OpenElement {
+    id: u32,
+    end: Option<u64>, // None for elements of unknown size
+}
+
+/// Extracts FLAC tracks (codec ID `A_FLAC`) from a Matroska or WebM file
+pub struct MatroskaFlacReader<R: Read> {
+    reader: BufReader<R>,
+    position: u64,
+    open_elements: Vec<OpenElement>,
+    timestamp_scale: u64,
+    cluster_timestamp: u64,
+    track_entry: Option<TrackEntry>,
+    tracks: Vec<MatroskaFlacTrack>,
+    pending: VecDeque<MatroskaFrame>,
+    limits: Limits,
+}
+
+impl MatroskaFlacReader<File> {
+    pub fn open(filename: &Path) -> Result<Self, Error> {
+        let file = File::open(filename)?;
+        Self::new(file)
+    }
+}
+
+impl<R: Read> MatroskaFlacReader<R> {
+    /// Reads the file up to the first cluster, collecting all FLAC tracks on the way
+    pub fn new(reader: R) -> Result<Self, Error> {
+        Self::with_limits(reader, Limits::DEFAULT)
+    }
+
+    /// Like `new`, but rejects elements and metadata beyond `limits`
+    pub fn with_limits(reader: R, limits: Limits) -> Result<Self, Error> {
+        let mut mkv = MatroskaFlacReader {
+            reader: BufReader::new(reader),
+            position: 0,
+            open_elements: Vec::new(),
+            timestamp_scale: 1_000_000,
+            cluster_timestamp: 0,
+            track_entry: None,
+            tracks: Vec::new(),
+            pending: VecDeque::new(),
+            limits,
+        };
+
+        match mkv.read_element_header()? {
+            Some((ID_EBML, Some(size))) => mkv.skip(size)?,
+            header => {
+                return Err(Error::invalid(
+                    Context::MatroskaElement(ID_EBML),
+                    Position::from_bits(0),
+                    header.map_or(0, |(id, _)| id as u64),
+                ))
+            }
+        }
+
+        while !mkv.in_element(ID_CLUSTER) {
+            if !mkv.read_element()? {
+                break;
+            }
+        }
+        mkv.close_elements()?;
+
+        Ok(mkv)
+    }
+
+    pub fn tracks(&self) -> &[MatroskaFlacTrack] {
+        &self.tracks
+    }
+
+    /// Returns the next frame of any FLAC track, or `None` at the end of the file
+    pub fn next_frame(&mut self) -> Result<Option<MatroskaFrame>, Error> {
+        while self.pending.is_empty() {
+            if !self.read_element()? {
+                return Ok(None);
+            }
+        }
+        Ok(self.pending.pop_front())
+    }
+
+    fn in_element(&self, id: u32) -> bool {
+        self.open_elements.iter().any(|element| element.id == id)
+    }
+
+    /// Closes every open element that ends at the current position
+    fn close_elements(&mut self) -> Result<(), Error> {
+        while let Some(element) = self.open_elements.last() {
+            match element.end {
+                Some(end) if end <= self.position => {
+                    let element = self.open_elements.pop().unwrap();
+                    self.end_element(element.id)?;
+                }
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn end_element(&mut self, id: u32) -> Result<(), Error> {
+        if id == ID_TRACK_ENTRY {
+            if let Some(entry) = self.track_entry.take() {
+                if &*entry.codec_id == b"A_FLAC" {
+                    self.add_track(entry)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_track(&mut self, entry: TrackEntry) -> Result<(), Error> {
+        let mut reader = BufferedBitstreamReader::new(&*entry.codec_private);
+        reader.set_limits(self.limits);
+        let (stream_info, metadata) = read_magic(&mut reader)
+            .and_then(|_| read_metadata_blocks(&mut reader))
+            .map_err(|e| e.within(Context::MatroskaElement(ID_CODEC_PRIVATE), None))?;
+
+        self.tracks.push(MatroskaFlacTrack {
+            track_number: entry.track_number,
+            stream_info,
+            metadata: metadata.into_boxed_slice(),
+        });
+        Ok(())
+    }
+
+    /// Handles a single element, returns false at the end of the file
+    fn read_element(&mut self) -> Result<bool, Error> {
+        self.close_elements()?;
+        let start = Position::from_bits(8 * self.position as usize);
+
+        let (id, size) = match self.read_element_header()? {
+            Some(header) => header,
+            None => {
+                while let Some(element) = self.open_elements.pop() {
+                    self.end_element(element.id)?;
+                }
+                return Ok(false);
+            }
+        };
+
+        // Clusters of unknown size are terminated by the next cluster
+        if id == ID_CLUSTER {
+            while self.in_element(ID_CLUSTER) {
+                let element = self.open_elements.pop().unwrap();
+                self.end_element(element.id)?;
+            }
+        }
+
+        if CONTAINERS.contains(&id) {
+            if id == ID_TRACK_ENTRY {
+                self.track_entry = Some(TrackEntry::default());
+            }
+            self.open_elements.push(OpenElement {
+                id,
+                end: size.map(|size| self.position + size),
+            });
+            return Ok(true);
+        }
+
+        // Only master elements may have an unknown size
+        let size = size.ok_or_else(|| Error::invalid(Context::MatroskaElement(id), start, !0))?;
+        match id {
+            ID_TIMESTAMP_SCALE => self.timestamp_scale = self.read_uint(size)?,
+            ID_TIMESTAMP => self.cluster_timestamp = self.read_uint(size)?,
+            ID_TRACK_NUMBER | ID_CODEC_ID | ID_CODEC_PRIVATE if self.track_entry.is_some() => {
+                let data = self.read_element_payload(id, start, size)?;
+                let entry = self.track_entry.as_mut().unwrap();
+                match id {
+                    ID_TRACK_NUMBER => entry.track_number = uint_from_bytes(&data),
+                    ID_CODEC_ID => entry.codec_id = data,
+                    _ => entry.codec_private = data,
+                }
+            }
+            ID_SIMPLE_BLOCK | ID_BLOCK => {
+                let data = self.read_element_payload(id, start, size)?;
+                self.queue_block(&data)
+                    .map_err(|e| e.within(Context::MatroskaElement(id), start))?;
+            }
+            _ => self.skip(size)?,
+        }
+        Ok(true)
+    }
+
+    fn queue_block(&mut self, data: &[u8]) -> Result<(), Error> {
+        let (track_number, length) = read_vint(data)?;
+        let track = match self
+            .tracks
+            .iter()
+            .find(|track| track.track_number == track_number)
+        {
+            Some(track) => track,
+            None => return Ok(()),
+        };
+
+        let header = data
+            .get(length..length + 3)
+            .ok_or_else(|| Error::invalid(Context::MatroskaBlock, None, data.len() as u64))?;
+        let relative_timestamp = i16::from_be_bytes([header[0], header[1]]) as i64;
+        let lacing = (header[2] >> 1) & 0b11;
+        let frames = split_laces(&data[length + 3..], lacing)?;
+
+        let timestamp = self
+            .cluster_timestamp
+            .saturating_add_signed(relative_timestamp);
+        let nanos = timestamp
+            .checked_mul(self.timestamp_scale)
+            .ok_or_else(|| Error::too_long(Context::MatroskaBlock, None, timestamp))?;
+        // Only the first frame of a lace has a timestamp, the rest follow from the samples before
+        // them. Counting from the block timestamp keeps rounding errors from adding up.
+        let mut num_samples = 0u64;
+        for payload in frames {
+            let mut reader = BufferedBitstreamReader::new(payload);
+            reader.set_limits(self.limits);
+            let frame = read_frame(&mut reader, &track.stream_info)?;
+            let offset = match frame.sample_rate {
+                0 => 0,
+                sample_rate => num_samples as u128 * 1_000_000_000 / sample_rate as u128,
+            };
+            let lace_nanos = nanos as u128 + offset;
+            if lace_nanos > u64::MAX as u128 {
+                return Err(Error::too_long(Context::MatroskaBlock, None, timestamp));
+            }
+            num_samples += frame.block_size as u64;
+            self.pending.push_back(MatroskaFrame {
+                track_number,
+                timestamp: Duration::from_nanos(lace_nanos as u64),
+                frame,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads an element ID and size, returns None at the end of the file
+    fn read_element_header(&mut self) -> Result<Option<(u32, Option<u64>)>, Error> {
+        let start = Position::from_bits(8 * self.position as usize);
+        let mut first = [0u8; 1];
+        if self.reader.read(&mut first)? == 0 {
+            return Ok(None);
+        }
+        self.position += 1;
+
+        let id_length = first[0].leading_zeros() as usize + 1;
+        if id_length > 4 {
+            return Err(Error::invalid(Context::EBMLInteger, start, first[0] as u64));
+        }
+        let mut id = first[0] as u32;
+        for byte in self.read_payload(id_length as u64 - 1)?.iter() {
+            id = id << 8 | *byte as u32;
+        }
+
+        let first = self.read_payload(1)?[0];
+        let size_length = first.leading_zeros() as usize + 1;
+        if size_length > 8 {
+            return Err(Error::invalid(
+                Context::EBMLInteger,
+                Position::from_bits(8 * self.position as usize - 8),
+                first as u64,
+            ));
+        }
+        let mut size = (first as u64) & (0xFF >> size_length);
+        let mut all_ones = size == 0xFF >> size_length;
+        for byte in self.read_payload(size_length as u64 - 1)?.iter() {
+            size = size << 8 | *byte as u64;
+            all_ones &= *byte == 0xFF;
+        }
+
+        Ok(Some((id, if all_ones { None } else { Some(size) })))
+    }
+
+    /// Reads the payload of element `id`, unless it is larger than the limits allow
+    fn read_element_payload(
+        &mut self,
+        id: u32,
+        start: Position,
+        size: u64,
+    ) -> Result<Box<[u8]>, Error> {
+        if size > self.limits.max_item_allocation as u64 {
+            return Err(Error::too_long(Context::MatroskaElement(id), start, size));
+        }
+        self.read_payload(size)
+    }
+
+    fn read_payload(&mut self, size: u64) -> Result<Box<[u8]>, Error> {
+        let mut data = Vec::new();
+        (&mut self.reader).take(size).read_to_end(&mut data)?;
+        if (data.len() as u64) < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.position += size;
+        Ok(data.into_boxed_slice())
+    }
+
+    fn read_uint(&mut self, size: u64) -> Result<u64, Error> {
+        if size > 8 {
+            return Err(Error::too_long(
+                Context::EBMLInteger,
+                Position::from_bits(8 * self.position as usize),
+                size,
+            ));
+        }
+        Ok(uint_from_bytes(&self.read_payload(size)?))
+    }
+
+    fn skip(&mut self, size: u64) -> Result<(), Error> {
+        let skipped = io::copy(&mut (&mut self.reader).take(size), &mut io::sink())?;
+        if skipped < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.position += size;
+        Ok(())
+    }
+}
+
+fn uint_from_bytes(data: &[u8]) -> u64 {
+    data.iter().fold(0, |acc, byte| acc << 8 | *byte as u64)
+}
+
+/// Reads a variable size integer with the length marker removed, returns it with its length
+fn read_vint(data: &[u8]) -> Result<(u64, usize), Error> {
+    let first = *data
+        .first()
+        .ok_or_else(|| Error::invalid(Context::EBMLInteger, None, 0))?;
+    let length = first.leading_zeros() as usize + 1;
+    if length > 8 || data.len() < length {
+        return Err(Error::invalid(Context::EBMLInteger, None, first as u64));
+    }
+    let value = data[1..length]
+        .iter()
+        .fold((first as u64) & (0xFF >> length), |acc, byte| {
+            acc << 8 | *byte as u64
+        });
+    Ok((value, length))
+}
+
+/// Splits the payload of a block into its frames according to the lacing mode
+fn split_laces(data: &[u8], lacing: u8) -> Result<Vec<&[u8]>, Error> {
+    if lacing == 0 {
+        return Ok(vec![data]);
+    }
+    let truncated = || Error::invalid(Context::MatroskaBlock, None, data.len() as u64);
+
+    let num_frames = *data.first().ok_or_else(truncated)? as usize + 1;
+    let mut pos = 1;
+    let mut sizes = Vec::with_capacity(num_frames);
+    match lacing {
+        // Xiph lacing
+        0b01 => {
+            for _ in 0..num_frames - 1 {
+                let mut size = 0;
+                loop {
+                    let byte = *data.get(pos).ok_or_else(truncated)?;
+                    pos += 1;
+                    size += byte as usize;
+                    if byte != 255 {
+                        break;
+                    }
+                }
+                sizes.push(size);
+            }
+        }
+        // Fixed-size lacing
+        0b10 => {
+            let remaining = data.len() - pos;
+            if !remaining.is_multiple_of(num_frames) {
+                return Err(Error::invalid(
+                    Context::MatroskaBlock,
+                    None,
+                    remaining as u64,
+                ));
+            }
+            let size = remaining / num_frames;
+            sizes.resize(num_frames - 1, size);
+        }
+        // EBML lacing, sizes after the first are signed differences
+        _ => {
+            let (first, length) = read_vint(data.get(pos..).ok_or_else(truncated)?)?;
+            pos += length;
+            sizes.push(first as usize);
+            let mut size = first as i64;
+            for _ in 1..num_frames - 1 {
+                let (raw, length) = read_vint(data.get(pos..).ok_or_else(truncated)?)?;
+                pos += length;
+                let bias = (1i64 << (7 * length - 1)) - 1;
+                size += raw as i64 - bias;
+                if size < 0 {
+                    return Err(Error::invalid(Context::MatroskaBlock, None, size as u64));
+                }
+                sizes.push(size as usize);
+            }
+        }
+    }
+
+    let mut frames = Vec::with_capacity(num_frames);
+    for size in sizes {
+        let frame = data.get(pos..pos + size).ok_or_else(truncated)?;
+        frames.push(frame);
+        pos += size;
+    }
+    frames.push(data.get(pos..).ok_or_else(truncated)?);
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::{crc16, crc8};
+
+    /// An element with its size coded in 8 bytes
+    fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut bytes: Vec<u8> = id
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|&b| b == 0)
+            .collect();
+        bytes.push(0x01);
+        bytes.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    fn uint_element(id: u32, value: u64) -> Vec<u8> {
+        element(id, &value.to_be_bytes())
+    }
+
+    /// `fLaC` and a STREAMINFO block for mono 16 bit audio in blocks of 192 samples
+    fn codec_private(sample_rate: u32) -> Vec<u8> {
+        let mut bytes = b"fLaC\x80\x00\x00\x22".to_vec();
+        bytes.extend_from_slice(&[0x00, 0xc0, 0x00, 0xc0, 0, 0, 0, 0, 0, 0]);
+        let num_samples = 192 * 4;
+        let packed = (sample_rate as u64) << 44 | 15 << 36 | num_samples;
+        bytes.extend_from_slice(&packed.to_be_bytes());
+        bytes.extend_from_slice(&[0u8; 16]);
+        bytes
+    }
+
+    /// A frame of 192 samples of silence, taking its sample rate from STREAMINFO
+    fn frame(frame_number: u8) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xf8, 0x10, 0x08, frame_number];
+        bytes.push(crc8(0, &bytes));
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00]);
+        let crc = crc16(0, &bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes
+    }
+
+    /// A SimpleBlock of track 1 holding `frames` in Xiph lacing
+    fn simple_block(relative_timestamp: i16, frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = vec![0x81];
+        payload.extend_from_slice(&relative_timestamp.to_be_bytes());
+        payload.push(0b10);
+        payload.push(frames.len() as u8 - 1);
+        for frame in &frames[..frames.len() - 1] {
+            payload.push(frame.len() as u8);
+        }
+        for frame in frames {
+            payload.extend_from_slice(frame);
+        }
+        element(ID_SIMPLE_BLOCK, &payload)
+    }
+
+    fn file(sample_rate: u32, timestamp_scale: u64, cluster: &[u8]) -> Vec<u8> {
+        let entry = [
+            uint_element(ID_TRACK_NUMBER, 1),
+            element(ID_CODEC_ID, b"A_FLAC"),
+            element(ID_CODEC_PRIVATE, &codec_private(sample_rate)),
+        ]
+        .concat();
+        let segment = [
+            element(ID_INFO, &uint_element(ID_TIMESTAMP_SCALE, timestamp_scale)),
+            element(ID_TRACKS, &element(ID_TRACK_ENTRY, &entry)),
+            element(ID_CLUSTER, cluster),
+        ]
+        .concat();
+        [element(ID_EBML, &[]), element(ID_SEGMENT, &segment)].concat()
+    }
+
+    fn read_all(data: &[u8]) -> Result<Vec<MatroskaFrame>, Error> {
+        let mut mkv = MatroskaFlacReader::new(data)?;
+        let mut frames = Vec::new();
+        while let Some(frame) = mkv.next_frame()? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn reads_laced_frames() {
+        let cluster = [
+            uint_element(ID_TIMESTAMP, 1000),
+            simple_block(-500, &[frame(0), frame(1)]),
+        ]
+        .concat();
+        let data = file(48000, 1_000_000, &cluster);
+
+        let mkv = MatroskaFlacReader::new(&data[..]).unwrap();
+        assert_eq!(mkv.tracks().len(), 1);
+        assert_eq!(mkv.tracks()[0].track_number, 1);
+        assert_eq!(mkv.tracks()[0].stream_info.sample_rate, 48000);
+
+        let frames = read_all(&data).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].timestamp, Duration::from_millis(500));
+        assert_eq!(frames[1].timestamp, Duration::from_millis(504));
+        assert_eq!(frames[1].frame.block_size, 192);
+    }
+
+    #[test]
+    fn lace_timestamps_do_not_drift() {
+        // 192 samples at 44.1 kHz is not a whole number of nanoseconds
+        let frames: Vec<Vec<u8>> = (0..4).map(frame).collect();
+        let frames = read_all(&file(44100, 1_000_000, &simple_block(0, &frames))).unwrap();
+        let nanos: Vec<u128> = frames
+            .iter()
+            .map(|frame| frame.timestamp.as_nanos())
+            .collect();
+        assert_eq!(nanos, [0, 4_353_741, 8_707_482, 13_061_224]);
+    }
+
+    #[test]
+    fn fixed_size_lacing() {
+        let data = [1, 10, 11, 20, 21];
+        assert_eq!(split_laces(&data, 0b10).unwrap(), [&data[1..3], &data[3..]]);
+        assert!(matches!(
+            split_laces(&data[..4], 0b10),
+            Err(Error::Invalid { value: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn zero_sample_rate() {
+        let cluster = simple_block(0, &[frame(0), frame(1)]);
+        let frames = read_all(&file(0, 1_000_000, &cluster)).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].timestamp, Duration::ZERO);
+    }
+
+    #[test]
+    fn timestamp_overflow() {
+        let cluster = [
+            uint_element(ID_TIMESTAMP, 1 << 40),
+            simple_block(0, &[frame(0)]),
+        ]
+        .concat();
+        assert!(matches!(
+            read_all(&file(48000, 1 << 30, &cluster)),
+            Err(Error::Within { .. })
+        ));
+
+        let cluster = [
+            uint_element(ID_TIMESTAMP, u64::MAX),
+            simple_block(i16::MAX, &[frame(0)]),
+        ]
+        .concat();
+        assert!(read_all(&file(48000, 2, &cluster)).is_err());
+    }
+
+    #[test]
+    fn payload_limit() {
+        let cluster = simple_block(0, &[frame(0)]);
+        let data = file(48000, 1_000_000, &cluster);
+        let limits = Limits {
+            max_item_allocation: 40,
+            ..Limits::DEFAULT
+        };
+        assert!(matches!(
+            MatroskaFlacReader::with_limits(&data[..], limits),
+            Err(Error::TooLong {
+                context: Context::MatroskaElement(ID_CODEC_PRIVATE),
+                value: 42,
+                ..
+            })
+        ));
+    }
+}
@@ -1,24 +1,47 @@
+use alloc::boxed::Box;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MetadataBlock {
     pub is_last: bool,
     pub content: MetadataBlockData,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum MetadataBlockData {
     StreamInfo(MetadataBlockStreamInfo),
-    Padding,                // No content
+    Padding(u32),           // Length of padding in bytes
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::base64_bytes"))]
     Application(Box<[u8]>), // Application defined
     SeekTable(MetadataBlockSeekTable),
-    VorbisComment(Box<[u8]>), // Parsing is Not Our Job™
+    VorbisComment(MetadataBlockVorbisComment),
     CueSheet(MetadataBlockCueSheet),
     Picture(MetadataBlockPicture),
     Reserved(u8),
     Invalid,
-    TodoUnimplemented(u8),
+}
+
+impl MetadataBlockData {
+    pub fn block_type(&self) -> u8 {
+        match self {
+            MetadataBlockData::StreamInfo(_) => 0,
+            MetadataBlockData::Padding(_) => 1,
+            MetadataBlockData::Application(_) => 2,
+            MetadataBlockData::SeekTable(_) => 3,
+            MetadataBlockData::VorbisComment(_) => 4,
+            MetadataBlockData::CueSheet(_) => 5,
+            MetadataBlockData::Picture(_) => 6,
+            MetadataBlockData::Reserved(n) => *n,
+            MetadataBlockData::Invalid => 127,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MetadataBlockStreamInfo {
     pub min_block_size: u16,
     pub max_block_size: u16,
@@ -28,15 +51,18 @@ pub struct MetadataBlockStreamInfo {
     pub num_channels: u8,       // 3 bits used
     pub sample_depth: u8,       // 5 bits used
     pub num_samples: u64,       // 36 bits used
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::hex_u128"))]
     pub decoded_checksum: u128, // MD5
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MetadataBlockSeekTable {
     pub seek_points: Box<[SeekPoint]>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SeekPoint {
     pub sample_number: u64,
     pub frame_offset: u64,
@@ -44,7 +70,18 @@ pub struct SeekPoint {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MetadataBlockVorbisComment {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::text_bytes"))]
+    pub vendor: Box<[u8]>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::text_bytes_list"))]
+    pub comments: Box<[Box<[u8]>]>, // NAME=value, UTF-8
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MetadataBlockCueSheet {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::text_bytes"))]
     pub catalog_number: Box<[u8]>,
     pub num_lead_in_samples: u64,
     pub is_cd: bool,
@@ -52,9 +89,11 @@ pub struct MetadataBlockCueSheet {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CueSheetTrack {
     pub track_offset: u64,
     pub track_num: u8,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::text_array"))]
     pub track_isrc: [u8; 12],
     pub track_type: bool,
     pub pre_emphasis: bool,
@@ -62,45 +101,110 @@ pub struct CueSheetTrack {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CueSheetTrackIndex {
     pub offset: u64,
     pub index_point: u8,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct MetadataBlockPicture {
     pub picture_type: PictureType,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::text_bytes"))]
     pub mime_type: Box<[u8]>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::text_bytes"))]
     pub description: Box<[u8]>,
     pub width: u32,
     pub height: u32,
     pub depth: u32,
     pub num_colors_used: u32,
+    #[cfg_attr(feature = "serde", serde(with = "crate::serde_helpers::base64_bytes"))]
     pub picture: Box<[u8]>,
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum PictureType {
-    Other = 0,
-    FileIcon32 = 1,
-    FileIcon = 2,
-    FrontCover = 3,
-    BackCover = 4,
-    Leaflet = 5,
-    Media = 6,
-    LeadArtist = 7,
-    Artist = 8,
-    Conductor = 9,
-    Band = 10,
-    Composer = 11,
-    Lyricist = 12,
-    RecordingLocation = 13,
-    DuringRecording = 14,
-    DuringPerformance = 15,
-    Movie = 16,
-    BrightlyColouredFish = 17,
-    Illustration = 18,
-    BandLogo = 19,
-    PublisherLogo = 20,
-    Reserved,
+    Other,
+    FileIcon32,
+    FileIcon,
+    FrontCover,
+    BackCover,
+    Leaflet,
+    Media,
+    LeadArtist,
+    Artist,
+    Conductor,
+    Band,
+    Composer,
+    Lyricist,
+    RecordingLocation,
+    DuringRecording,
+    DuringPerformance,
+    Movie,
+    BrightlyColouredFish,
+    Illustration,
+    BandLogo,
+    PublisherLogo,
+    Reserved(u32),
+}
+
+impl PictureType {
+    /// The number the format stores for this picture type
+    pub fn code(&self) -> u32 {
+        match self {
+            PictureType::Other => 0,
+            PictureType::FileIcon32 => 1,
+            PictureType::FileIcon => 2,
+            PictureType::FrontCover => 3,
+            PictureType::BackCover => 4,
+            PictureType::Leaflet => 5,
+            PictureType::Media => 6,
+            PictureType::LeadArtist => 7,
+            PictureType::Artist => 8,
+            PictureType::Conductor => 9,
+            PictureType::Band => 10,
+            PictureType::Composer => 11,
+            PictureType::Lyricist => 12,
+            PictureType::RecordingLocation => 13,
+            PictureType::DuringRecording => 14,
+            PictureType::DuringPerformance => 15,
+            PictureType::Movie => 16,
+            PictureType::BrightlyColouredFish => 17,
+            PictureType::Illustration => 18,
+            PictureType::BandLogo => 19,
+            PictureType::PublisherLogo => 20,
+            PictureType::Reserved(n) => *n,
+        }
+    }
+}
+
+impl From<u32> for PictureType {
+    fn from(picture_type: u32) -> Self {
+        match picture_type {
+            0 => PictureType::Other,
+            1 => PictureType::FileIcon32,
+            2 => PictureType::FileIcon,
+            3 => PictureType::FrontCover,
+            4 => PictureType::BackCover,
+            5 => PictureType::Leaflet,
+            6 => PictureType::Media,
+            7 => PictureType::LeadArtist,
+            8 => PictureType::Artist,
+            9 => PictureType::Conductor,
+            10 => PictureType::Band,
+            11 => PictureType::Composer,
+            12 => PictureType::Lyricist,
+            13 => PictureType::RecordingLocation,
+            14 => PictureType::DuringRecording,
+            15 => PictureType::DuringPerformance,
+            16 => PictureType::Movie,
+            17 => PictureType::BrightlyColouredFish,
+            18 => PictureType::Illustration,
+            19 => PictureType::BandLogo,
+            20 => PictureType::PublisherLogo,
+            n => PictureType::Reserved(n),
+        }
+    }
 }
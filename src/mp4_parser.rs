@@ -0,0 +1,632 @@
+use crate::bitstream::BufferedBitstreamReader;
+use crate::block_parser::read_metadata_blocks;
+use crate::error::{Context, Error, Position};
+use crate::frame_parser::read_frame;
+use crate::frame_types::Frame;
+use crate::limits::Limits;
+use crate::metadata_types::{MetadataBlock, MetadataBlockStreamInfo};
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+type FourCC = [u8; 4];
+
+#[derive(Debug, Clone)]
+pub struct Mp4FlacTrack {
+    pub track_id: u32,
+    pub stream_info: MetadataBlockStreamInfo,
+    pub metadata: Box<[MetadataBlock]>, // Including STREAMINFO, as stored in dfLa
+    pub samples: Box<[Mp4Sample]>,
+}
+
+/// Location of a single sample, which for FLAC is exactly one frame
+#[derive(Debug, Copy, Clone)]
+pub struct Mp4Sample {
+    pub offset: u64,
+    pub size: u32,
+}
+
+/// Extracts FLAC tracks (sample entry `fLaC`) from an ISO base media file such as MP4
+pub struct Mp4FlacReader<R: Read + Seek> {
+    reader: R,
+    tracks: Vec<Mp4FlacTrack>,
+    limits: Limits,
+}
+
+impl Mp4FlacReader<File> {
+    pub fn open(filename: &Path) -> Result<Self, Error> {
+        let file = File::open(filename)?;
+        Self::new(file)
+    }
+}
+
+impl<R: Read + Seek> Mp4FlacReader<R> {
+    /// Locates the `moov` box and reads the sample tables of all FLAC tracks
+    pub fn new(reader: R) -> Result<Self, Error> {
+        Self::with_limits(reader, Limits::DEFAULT)
+    }
+
+    /// Like `new`, but rejects boxes, sample tables and samples beyond `limits`
+    pub fn with_limits(mut reader: R, limits: Limits) -> Result<Self, Error> {
+        let file_start = reader.stream_position()?;
+        let file_length = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(file_start))?;
+
+        let moov = loop {
+            let start = Position::from_bits(8 * reader.stream_position()? as usize);
+            let (box_type, size) = match read_box_header(&mut reader)? {
+                Some(header) => header,
+                None => return Err(Error::missing(Context::Mp4Box(*b"moov"), None)),
+            };
+            if &box_type == b"moov" {
+                let max_size = limits.max_item_allocation as u64;
+                if let Some(size) = size.filter(|&size| size > max_size) {
+                    return Err(Error::too_long(Context::Mp4Box(box_type), start, size));
+                }
+                let mut moov = Vec::new();
+                (&mut reader)
+                    .take(size.unwrap_or(max_size + 1))
+                    .read_to_end(&mut moov)?;
+                if moov.len() as u64 > max_size {
+                    return Err(Error::too_long(
+                        Context::Mp4Box(box_type),
+                        start,
+                        moov.len() as u64,
+                    ));
+                }
+                if size.is_some_and(|size| (moov.len() as u64) < size) {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                break Mp4Box {
+                    box_type,
+                    data: moov,
+                };
+            }
+            match size.and_then(|size| i64::try_from(size).ok()) {
+                Some(size) => reader.seek(SeekFrom::Current(size))?,
+                None => return Err(Error::missing(Context::Mp4Box(*b"moov"), start)),
+            };
+        };
+
+        let mut tracks = Vec::new();
+        for trak in moov.as_ref().children()? {
+            if &trak.box_type == b"trak" {
+                if let Some(track) = read_track(trak, limits, file_length)? {
+                    tracks.push(track);
+                }
+            }
+        }
+
+        Ok(Mp4FlacReader {
+            reader,
+            tracks,
+            limits,
+        })
+    }
+
+    pub fn tracks(&self) -> &[Mp4FlacTrack] {
+        &self.tracks
+    }
+
+    /// Reads and parses sample number `sample` of the track at index `track`
+    pub fn read_sample(&mut self, track: usize, sample: usize) -> Result<Frame, Error> {
+        let track = self
+            .tracks
+            .get(track)
+            .ok_or_else(|| Error::invalid(Context::Mp4Track, None, track as u64))?;
+        let location = track
+            .samples
+            .get(sample)
+            .ok_or_else(|| Error::invalid(Context::Mp4Sample(sample), None, sample as u64))?;
+
+        self.reader.seek(SeekFrom::Start(location.offset))?;
+        let mut data = vec![0u8; location.size as usize];
+        self.reader.read_exact(&mut data)?;
+
+        let mut reader = BufferedBitstreamReader::new(&*data);
+        reader.set_limits(self.limits);
+        read_frame(&mut reader, &track.stream_info).map_err(|e| {
+            e.within(
+                Context::Mp4Sample(sample),
+                Position::from_bits(8 * location.offset as usize),
+            )
+        })
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Mp4Box<T> {
+    box_type: FourCC,
+    data: T,
+}
+
+impl Mp4Box<Vec<u8>> {
+    fn as_ref(&self) -> Mp4Box<&[u8]> {
+        Mp4Box {
+            box_type: self.box_type,
+            data: &self.data,
+        }
+    }
+}
+
+impl<'a> Mp4Box<&'a [u8]> {
+    fn truncated(&self) -> Error {
+        Error::invalid(Context::Mp4Box(self.box_type), None, self.data.len() as u64)
+    }
+
+    fn read_u32(&self, pos: usize) -> Result<u32, Error> {
+        let bytes = self
+            .data
+            .get(pos..pos + 4)
+            .ok_or_else(|| self.truncated())?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_u64(&self, pos: usize) -> Result<u64, Error> {
+        let bytes = self
+            .data
+            .get(pos..pos + 8)
+            .ok_or_else(|| self.truncated())?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// The box with its first `pos` bytes of content removed
+    fn skip(&self, pos: usize) -> Result<Self, Error> {
+        Ok(Mp4Box {
+            box_type: self.box_type,
+            data: self.data.get(pos..).ok_or_else(|| self.truncated())?,
+        })
+    }
+
+    /// Splits the content of a container box into its children
+    fn children(&self) -> Result<Vec<Mp4Box<&'a [u8]>>, Error> {
+        let mut data = self.data;
+        let mut children = Vec::new();
+        while !data.is_empty() {
+            let header = Mp4Box {
+                box_type: self.box_type,
+                data,
+            };
+            let box_type = data
+                .get(4..8)
+                .ok_or_else(|| header.truncated())?
+                .try_into()
+                .unwrap();
+            let (header_size, size) = match header.read_u32(0)? {
+                0 => (8, data.len()),
+                1 => (16, header.read_u64(8)? as usize),
+                size => (8, size as usize),
+            };
+            let invalid_size = || Error::invalid(Context::Mp4Box(box_type), None, size as u64);
+            if size < header_size {
+                return Err(invalid_size());
+            }
+            let child = data.get(header_size..size).ok_or_else(invalid_size)?;
+            children.push(Mp4Box {
+                box_type,
+                data: child,
+            });
+            data = &data[size..];
+        }
+        Ok(children)
+    }
+
+    fn find(&self, path: &[&FourCC]) -> Result<Option<Mp4Box<&'a [u8]>>, Error> {
+        let (first, rest) = match path.split_first() {
+            Some(split) => split,
+            None => return Ok(Some(*self)),
+        };
+        for child in self.children()? {
+            if &child.box_type == *first {
+                return child.find(rest);
+            }
+        }
+        Ok(None)
+    }
+
+    fn find_required(&self, box_type: &FourCC) -> Result<Mp4Box<&'a [u8]>, Error> {
+        self.find(&[box_type])?
+            .ok_or_else(|| Error::missing(Context::Mp4Box(*box_type), None))
+    }
+}
+
+/// Reads a box header from a stream, the size excludes the header and is None for a box that
+/// extends to the end of the file
+fn read_box_header(reader: &mut dyn Read) -> Result<Option<(FourCC, Option<u64>)>, Error> {
+    let mut header = [0u8; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let box_type: FourCC = header[4..8].try_into().unwrap();
+    let invalid_size = |size| Error::invalid(Context::Mp4Box(box_type), None, size);
+    let size = match u32::from_be_bytes(header[0..4].try_into().unwrap()) {
+        0 => None,
+        1 => {
+            let mut large_size = [0u8; 8];
+            reader.read_exact(&mut large_size)?;
+            let large_size = u64::from_be_bytes(large_size);
+            Some(
+                large_size
+                    .checked_sub(16)
+                    .ok_or_else(|| invalid_size(large_size))?,
+            )
+        }
+        size => Some(
+            (size as u64)
+                .checked_sub(8)
+                .ok_or_else(|| invalid_size(size as u64))?,
+        ),
+    };
+    Ok(Some((box_type, size)))
+}
+
+fn read_track(
+    trak: Mp4Box<&[u8]>,
+    limits: Limits,
+    file_length: u64,
+) -> Result<Option<Mp4FlacTrack>, Error> {
+    let stbl = match trak.find(&[b"mdia", b"minf", b"stbl"])? {
+        Some(stbl) => stbl,
+        None => return Ok(None),
+    };
+
+    // stsd is a full box followed by an entry count, the entries are boxes themselves
+    let stsd = stbl.find_required(b"stsd")?;
+    let flac_entry = match stsd
+        .skip(8)?
+        .children()?
+        .into_iter()
+        .find(|entry| &entry.box_type == b"fLaC")
+    {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    // Skip the fields of AudioSampleEntry to get to the child boxes
+    let dfla = flac_entry.skip(28)?.find_required(b"dfLa")?;
+    let (stream_info, metadata) = read_dfla(dfla, limits)?;
+
+    let tkhd = trak.find_required(b"tkhd")?;
+    let track_id = if tkhd.data.first() == Some(&1) {
+        tkhd.read_u32(20)?
+    } else {
+        tkhd.read_u32(12)?
+    };
+
+    Ok(Some(Mp4FlacTrack {
+        track_id,
+        stream_info,
+        metadata: metadata.into_boxed_slice(),
+        samples: read_sample_table(stbl, limits, file_length)?,
+    }))
+}
+
+fn read_dfla(
+    dfla: Mp4Box<&[u8]>,
+    limits: Limits,
+) -> Result<(MetadataBlockStreamInfo, Vec<MetadataBlock>), Error> {
+    let version = *dfla.data.first().ok_or_else(|| dfla.truncated())?;
+    if version != 0 {
+        return Err(Error::invalid(
+            Context::Mp4Box(dfla.box_type),
+            None,
+            version as u64,
+        ));
+    }
+
+    let mut reader = BufferedBitstreamReader::new(dfla.skip(4)?.data);
+    reader.set_limits(limits);
+    read_metadata_blocks(&mut reader).map_err(|e| e.within(Context::Mp4Box(dfla.box_type), None))
+}
+
+/// Combines stsz, stsc and stco/co64 into the location of every sample, checking that each
+/// sample lies within the file
+fn read_sample_table(
+    stbl: Mp4Box<&[u8]>,
+    limits: Limits,
+    file_length: u64,
+) -> Result<Box<[Mp4Sample]>, Error> {
+    let stsz = stbl.find_required(b"stsz")?;
+    let uniform_size = stsz.read_u32(4)?;
+    let num_samples = stsz.read_u32(8)? as usize;
+    // Explicit sizes take 4 bytes each, so the box bounds their number
+    if uniform_size == 0 && num_samples > stsz.data.len().saturating_sub(12) / 4 {
+        return Err(stsz.truncated());
+    }
+    if num_samples.saturating_mul(core::mem::size_of::<Mp4Sample>()) > limits.max_item_allocation {
+        return Err(Error::too_long(
+            Context::Mp4Box(stsz.box_type),
+            None,
+            num_samples as u64,
+        ));
+    }
+    let sizes = (0..num_samples).map(|idx| match uniform_size {
+        0 => stsz.read_u32(12 + 4 * idx),
+        size => Ok(size),
+    });
+
+    let chunk_offsets = if let Some(stco) = stbl.find(&[b"stco"])? {
+        (0..stco.read_u32(4)? as usize)
+            .map(|idx| stco.read_u32(8 + 4 * idx).map(|offset| offset as u64))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        let co64 = stbl.find_required(b"co64")?;
+        (0..co64.read_u32(4)? as usize)
+            .map(|idx| co64.read_u64(8 + 8 * idx))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    // Each stsc entry gives the samples per chunk from its first chunk until the next entry, so
+    // the first chunks have to increase
+    let stsc = stbl.find_required(b"stsc")?;
+    let mut runs = Vec::new();
+    let mut previous_chunk = 0;
+    for idx in 0..stsc.read_u32(4)? as usize {
+        let first_chunk = stsc.read_u32(8 + 12 * idx)?;
+        if first_chunk <= previous_chunk {
+            return Err(Error::invalid(
+                Context::Mp4Box(stsc.box_type),
+                None,
+                first_chunk as u64,
+            ));
+        }
+        previous_chunk = first_chunk;
+        runs.push((first_chunk, stsc.read_u32(12 + 12 * idx)?));
+    }
+
+    let mut samples = Vec::with_capacity(num_samples);
+    let mut sizes = sizes.into_iter();
+    let mut runs = runs.into_iter().peekable();
+    let mut samples_per_chunk = 0;
+    for (chunk_idx, chunk_offset) in chunk_offsets.into_iter().enumerate() {
+        let chunk_number = chunk_idx as u32 + 1;
+        while let Some((_, run_samples)) = runs.next_if(|(first, _)| *first <= chunk_number) {
+            samples_per_chunk = run_samples;
+        }
+
+        let mut offset = chunk_offset;
+        for size in (&mut sizes).take(samples_per_chunk as usize) {
+            let size = size?;
+            let sample = samples.len();
+            if size as usize > limits.max_item_allocation {
+                return Err(Error::too_long(
+                    Context::Mp4Sample(sample),
+                    None,
+                    size as u64,
+                ));
+            }
+            let end = offset
+                .checked_add(size as u64)
+                .filter(|&end| end <= file_length)
+                .ok_or_else(|| Error::invalid(Context::Mp4Sample(sample), None, offset))?;
+            samples.push(Mp4Sample { offset, size });
+            offset = end;
+        }
+    }
+
+    if samples.len() != num_samples {
+        return Err(Error::invalid(
+            Context::Mp4Box(*b"stsc"),
+            None,
+            samples.len() as u64,
+        ));
+    }
+    Ok(samples.into_boxed_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crc::{crc16, crc8};
+    use std::io::Cursor;
+
+    fn mp4_box(box_type: &FourCC, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(box_type);
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// A version 0 full box whose payload starts with the given 32-bit fields
+    fn full_box(box_type: &FourCC, fields: &[u32]) -> Vec<u8> {
+        let mut payload = vec![0u8; 4];
+        for field in fields {
+            payload.extend_from_slice(&field.to_be_bytes());
+        }
+        mp4_box(box_type, &payload)
+    }
+
+    /// A frame of 192 samples of silence in mono 16 bit audio
+    fn frame(frame_number: u8) -> Vec<u8> {
+        let mut bytes = vec![0xff, 0xf8, 0x19, 0x08, frame_number];
+        bytes.push(crc8(0, &bytes));
+        bytes.extend_from_slice(&[0x00, 0x00, 0x00]);
+        let crc = crc16(0, &bytes);
+        bytes.extend_from_slice(&crc.to_be_bytes());
+        bytes
+    }
+
+    fn dfla() -> Vec<u8> {
+        let mut payload = vec![0, 0, 0, 0, 0x80, 0x00, 0x00, 0x22];
+        payload.extend_from_slice(&[0x00, 0xc0, 0x00, 0xc0, 0, 0, 0, 0, 0, 0]);
+        let num_samples = 192 * 2;
+        let packed = 44100u64 << 44 | 15 << 36 | num_samples;
+        payload.extend_from_slice(&packed.to_be_bytes());
+        payload.extend_from_slice(&[0u8; 16]);
+        mp4_box(b"dfLa", &payload)
+    }
+
+    /// A file with an `mdat` box holding `frames` followed by a `moov` box with one track that
+    /// stores them as a single chunk, described by the given `stsz`
+    fn file(frames: &[Vec<u8>], stsz: &[u8]) -> Vec<u8> {
+        let stsc = full_box(b"stsc", &[1, 1, frames.len() as u32, 1]);
+        let stco = full_box(b"stco", &[1, 8]);
+        file_with_chunks(frames, stsz, &stsc, &stco)
+    }
+
+    /// Like `file`, with the chunks given by `stsc` and `stco`. `mdat` starts at offset 8.
+    fn file_with_chunks(frames: &[Vec<u8>], stsz: &[u8], stsc: &[u8], stco: &[u8]) -> Vec<u8> {
+        let mdat = mp4_box(b"mdat", &frames.concat());
+
+        let mut entry = vec![0u8; 28];
+        entry.extend_from_slice(&dfla());
+        let stsd = [
+            vec![0u8; 4],
+            1u32.to_be_bytes().to_vec(),
+            mp4_box(b"fLaC", &entry),
+        ]
+        .concat();
+        let stbl = [
+            mp4_box(b"stsd", &stsd),
+            stsz.to_vec(),
+            stsc.to_vec(),
+            stco.to_vec(),
+        ]
+        .concat();
+        let minf = mp4_box(b"minf", &mp4_box(b"stbl", &stbl));
+        let trak = [full_box(b"tkhd", &[0, 0, 7]), mp4_box(b"mdia", &minf)].concat();
+        [mdat, mp4_box(b"moov", &mp4_box(b"trak", &trak))].concat()
+    }
+
+    fn explicit_sizes(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut fields = vec![0, frames.len() as u32];
+        fields.extend(frames.iter().map(|frame| frame.len() as u32));
+        full_box(b"stsz", &fields)
+    }
+
+    #[test]
+    fn reads_samples() {
+        let frames = [frame(0), frame(1)];
+        let data = file(&frames, &explicit_sizes(&frames));
+
+        let mut mp4 = Mp4FlacReader::new(Cursor::new(data)).unwrap();
+        assert_eq!(mp4.tracks().len(), 1);
+        assert_eq!(mp4.tracks()[0].track_id, 7);
+        assert_eq!(mp4.tracks()[0].samples.len(), 2);
+        assert_eq!(
+            mp4.tracks()[0].samples[1].offset,
+            8 + frames[0].len() as u64
+        );
+        assert_eq!(mp4.read_sample(0, 1).unwrap().block_size, 192);
+        assert!(mp4.read_sample(0, 2).is_err());
+    }
+
+    #[test]
+    fn reads_chunks_of_different_sizes() {
+        let frames = [frame(0), frame(1), frame(2), frame(3)];
+        let length = frames[0].len() as u32;
+        // Chunks of 1, 1 and 2 samples, with a gap of 100 bytes before the last one
+        let stsc = full_box(b"stsc", &[2, 1, 1, 1, 3, 2, 1]);
+        let stco = full_box(b"stco", &[3, 8, 8 + length, 8 + 2 * length + 100]);
+        let mut mdat_frames = frames[..2].to_vec();
+        mdat_frames.push(vec![0u8; 100]);
+        mdat_frames.extend_from_slice(&frames[2..]);
+        let data = file_with_chunks(&mdat_frames, &explicit_sizes(&frames), &stsc, &stco);
+
+        let mut mp4 = Mp4FlacReader::new(Cursor::new(data)).unwrap();
+        let offsets: Vec<u64> = mp4.tracks()[0]
+            .samples
+            .iter()
+            .map(|sample| sample.offset)
+            .collect();
+        let length = length as u64;
+        assert_eq!(
+            offsets,
+            [8, 8 + length, 8 + 2 * length + 100, 8 + 3 * length + 100]
+        );
+        assert_eq!(mp4.read_sample(0, 3).unwrap().block_size, 192);
+    }
+
+    #[test]
+    fn stsc_first_chunks_must_increase() {
+        let frames = [frame(0), frame(1)];
+        let stco = full_box(b"stco", &[1, 8]);
+        for first_chunks in [[1, 1], [2, 1], [0, 1]] {
+            let stsc = full_box(b"stsc", &[2, first_chunks[0], 1, 1, first_chunks[1], 1, 1]);
+            let data = file_with_chunks(&frames, &explicit_sizes(&frames), &stsc, &stco);
+            assert!(matches!(
+                Mp4FlacReader::new(Cursor::new(data)),
+                Err(Error::Invalid {
+                    context: Context::Mp4Box(box_type),
+                    ..
+                }) if &box_type == b"stsc"
+            ));
+        }
+    }
+
+    #[test]
+    fn uniform_sample_count_is_bounded() {
+        let frames = [frame(0), frame(1)];
+        let stsz = full_box(b"stsz", &[frames[0].len() as u32, u32::MAX]);
+        assert!(matches!(
+            Mp4FlacReader::new(Cursor::new(file(&frames, &stsz))),
+            Err(Error::TooLong {
+                context: Context::Mp4Box(box_type),
+                ..
+            }) if &box_type == b"stsz"
+        ));
+    }
+
+    #[test]
+    fn explicit_sample_count_is_bounded_by_box() {
+        let frames = [frame(0), frame(1)];
+        let stsz = full_box(b"stsz", &[0, 1000, 12, 12]);
+        assert!(Mp4FlacReader::new(Cursor::new(file(&frames, &stsz))).is_err());
+    }
+
+    #[test]
+    fn samples_must_fit_in_file() {
+        let frames = [frame(0), frame(1)];
+        let stsz = full_box(b"stsz", &[0, 2, 12, 100_000]);
+        assert!(matches!(
+            Mp4FlacReader::new(Cursor::new(file(&frames, &stsz))),
+            Err(Error::Invalid {
+                context: Context::Mp4Sample(1),
+                ..
+            })
+        ));
+
+        let limits = Limits {
+            max_item_allocation: 500,
+            ..Limits::DEFAULT
+        };
+        let stsz = full_box(b"stsz", &[0, 2, 12, 1000]);
+        let data = [file(&frames, &stsz), vec![0u8; 1000]].concat();
+        assert!(matches!(
+            Mp4FlacReader::with_limits(Cursor::new(data), limits),
+            Err(Error::TooLong {
+                context: Context::Mp4Sample(1),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn huge_box_before_moov() {
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(b"free");
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(Mp4FlacReader::new(Cursor::new(data)).is_err());
+    }
+
+    #[test]
+    fn moov_size_is_limited() {
+        let frames = [frame(0)];
+        let data = file(&frames, &explicit_sizes(&frames));
+        let limits = Limits {
+            max_item_allocation: 100,
+            ..Limits::DEFAULT
+        };
+        assert!(matches!(
+            Mp4FlacReader::with_limits(Cursor::new(data), limits),
+            Err(Error::TooLong {
+                context: Context::Mp4Box(box_type),
+                ..
+            }) if &box_type == b"moov"
+        ));
+    }
+}
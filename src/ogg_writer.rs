@@ -0,0 +1,406 @@
+use crate::bitstream::{BitstreamWriter, BufferedBitstreamWriter};
+use crate::block_writer::{write_metadata_block, write_stream_info_block};
+use crate::crc::ogg_crc32;
+use crate::error::{Context, Error};
+use crate::metadata_types::{
+    MetadataBlock, MetadataBlockData, MetadataBlockStreamInfo, MetadataBlockVorbisComment,
+};
+use std::io::Write;
+
+const HEADER_TYPE_CONTINUED: u8 = 0x01;
+const HEADER_TYPE_BOS: u8 = 0x02;
+const HEADER_TYPE_EOS: u8 = 0x04;
+
+/// Pages are flushed once they hold at least this many bytes, like libogg does
+const PAGE_TARGET_SIZE: usize = 4096;
+
+/// Granule position of a page on which no packet ends
+const NO_GRANULE: u64 = !0;
+
+/// Splits packets into Ogg pages for a single logical bitstream
+pub struct OggPageWriter<W: Write> {
+    writer: W,
+    serial: u32,
+    sequence: u32,
+    segments: Vec<u8>,
+    data: Vec<u8>,
+    granule: u64,    // granule position of the last packet ending on the pending page
+    continued: bool, // whether the pending page starts in the middle of a packet
+    held: Option<Vec<u8>>, // the last complete page, held back until it is known to be the last
+}
+
+impl<W: Write> OggPageWriter<W> {
+    pub fn new(writer: W, serial: u32) -> Self {
+        OggPageWriter {
+            writer,
+            serial,
+            sequence: 0,
+            segments: Vec::new(),
+            data: Vec::new(),
+            granule: NO_GRANULE,
+            continued: false,
+            held: None,
+        }
+    }
+
+    /// Queues a packet, `granule` is the granule position at the end of the packet
+    pub fn write_packet(&mut self, packet: &[u8], granule: u64) -> Result<(), Error> {
+        if self.data.len() >= PAGE_TARGET_SIZE {
+            self.flush_page()?;
+        }
+
+        let mut first_segment = true;
+        let mut last_len = 0;
+        for chunk in packet.chunks(255) {
+            self.push_segment(chunk, first_segment)?;
+            first_segment = false;
+            last_len = chunk.len();
+        }
+        // A packet whose length is a multiple of 255 is terminated by an empty segment
+        if last_len == 255 || packet.is_empty() {
+            self.push_segment(&[], first_segment)?;
+        }
+
+        self.granule = granule;
+        Ok(())
+    }
+
+    fn push_segment(&mut self, chunk: &[u8], first_segment: bool) -> Result<(), Error> {
+        if self.segments.len() == 255 {
+            self.write_page()?;
+            self.continued = !first_segment;
+        }
+        self.segments.push(chunk.len() as u8);
+        self.data.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    /// Writes out any queued segments as a page, even if the page is not full
+    pub fn flush_page(&mut self) -> Result<(), Error> {
+        if self.segments.is_empty() {
+            return Ok(());
+        }
+        self.write_page()
+    }
+
+    /// Writes the final page of the stream with the end of stream flag set and returns the
+    /// underlying writer. A stream without packets gets a single empty page.
+    pub fn finish(mut self) -> Result<W, Error> {
+        if !self.segments.is_empty() || self.held.is_none() {
+            if self.segments.is_empty() {
+                self.granule = 0;
+            }
+            self.write_page()?;
+        }
+        if let Some(mut page) = self.held.take() {
+            page[5] |= HEADER_TYPE_EOS;
+            page[22..26].fill(0);
+            let crc = ogg_crc32(0, &page);
+            page[22..26].copy_from_slice(&crc.to_le_bytes());
+            self.writer.write_all(&page)?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    /// Completes the pending page and writes out the one held before it
+    fn write_page(&mut self) -> Result<(), Error> {
+        let mut header_type = 0;
+        if self.sequence == 0 {
+            header_type |= HEADER_TYPE_BOS;
+        }
+        if self.continued {
+            header_type |= HEADER_TYPE_CONTINUED;
+        }
+
+        let mut page = Vec::with_capacity(27 + self.segments.len() + self.data.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // stream structure version
+        page.push(header_type);
+        page.extend_from_slice(&self.granule.to_le_bytes());
+        page.extend_from_slice(&self.serial.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0u8; 4]); // checksum, filled in below
+        page.push(self.segments.len() as u8);
+        page.extend_from_slice(&self.segments);
+        page.extend_from_slice(&self.data);
+
+        let crc = ogg_crc32(0, &page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        if let Some(previous) = self.held.replace(page) {
+            self.writer.write_all(&previous)?;
+        }
+
+        self.sequence += 1;
+        self.segments.clear();
+        self.data.clear();
+        self.granule = NO_GRANULE;
+        self.continued = false;
+        Ok(())
+    }
+}
+
+/// Muxes FLAC metadata and encoded frames into an Ogg FLAC stream (mapping version 1.0)
+pub struct OggFlacWriter<W: Write> {
+    pages: OggPageWriter<W>,
+    num_samples: u64,
+}
+
+impl<W: Write> OggFlacWriter<W> {
+    /// Writes the header packets. `metadata` may include the STREAMINFO block, which is skipped,
+    /// and the VORBIS_COMMENT block is moved to the front as the mapping requires.
+    pub fn new(
+        writer: W,
+        serial: u32,
+        stream_info: &MetadataBlockStreamInfo,
+        metadata: &[MetadataBlock],
+    ) -> Result<Self, Error> {
+        let mut blocks: Vec<MetadataBlockData> = metadata
+            .iter()
+            .map(|block| block.content.clone())
+            .filter(|data| !matches!(data, MetadataBlockData::StreamInfo(_)))
+            .collect();
+        match blocks
+            .iter()
+            .position(|data| matches!(data, MetadataBlockData::VorbisComment(_)))
+        {
+            Some(idx) => {
+                let comment = blocks.remove(idx);
+                blocks.insert(0, comment);
+            }
+            None => blocks.insert(0, MetadataBlockData::VorbisComment(empty_vorbis_comment())),
+        }
+
+        if blocks.len() > u16::MAX as usize {
+            return Err(Error::too_long(
+                Context::OggHeader,
+                None,
+                blocks.len() as u64,
+            ));
+        }
+
+        let mut pages = OggPageWriter::new(writer, serial);
+
+        let mut first_packet = BufferedBitstreamWriter::new(Vec::new());
+        first_packet.write_bytes(b"\x7fFLAC")?;
+        first_packet.write_unsigned(1, 8)?; // mapping major version
+        first_packet.write_unsigned(0, 8)?; // mapping minor version
+        first_packet.write_unsigned(blocks.len() as u128, 16)?;
+        first_packet.write_bytes(b"fLaC")?;
+        first_packet.write_bit(false)?; // at least the comment block follows
+        first_packet.write_unsigned(0, 7)?;
+        first_packet.write_unsigned(34, 24)?;
+        write_stream_info_block(&mut first_packet, stream_info)?;
+        pages.write_packet(&first_packet.finish()?, 0)?;
+        pages.flush_page()?;
+
+        let num_blocks = blocks.len();
+        for (idx, content) in blocks.into_iter().enumerate() {
+            let block = MetadataBlock {
+                is_last: idx + 1 == num_blocks,
+                content,
+            };
+            let mut packet = BufferedBitstreamWriter::new(Vec::new());
+            write_metadata_block(&mut packet, &block)?;
+            pages.write_packet(&packet.finish()?, 0)?;
+        }
+        // Audio data has to start on a fresh page
+        pages.flush_page()?;
+
+        Ok(OggFlacWriter {
+            pages,
+            num_samples: 0,
+        })
+    }
+
+    /// Queues one encoded frame containing `block_size` samples per channel
+    pub fn write_frame(&mut self, frame: &[u8], block_size: u32) -> Result<(), Error> {
+        self.num_samples += block_size as u64;
+        self.pages.write_packet(frame, self.num_samples)
+    }
+
+    /// Writes the last page with the end of stream flag set and returns the underlying writer
+    pub fn finish(self) -> Result<W, Error> {
+        self.pages.finish()
+    }
+}
+
+fn empty_vorbis_comment() -> MetadataBlockVorbisComment {
+    MetadataBlockVorbisComment {
+        vendor: Box::from(&b"muflac"[..]),
+        comments: Box::new([]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    /// A page split back into its fields
+    struct Page {
+        header_type: u8,
+        granule: u64,
+        sequence: u32,
+        segments: Vec<u8>,
+        data: Vec<u8>,
+    }
+
+    /// Splits a stream into pages, checking the capture pattern and checksum of each
+    fn read_pages(mut bytes: &[u8]) -> Vec<Page> {
+        let mut pages = Vec::new();
+        while !bytes.is_empty() {
+            assert_eq!(&bytes[..4], b"OggS");
+            let num_segments = bytes[26] as usize;
+            let segments = bytes[27..27 + num_segments].to_vec();
+            let length = 27 + num_segments + segments.iter().map(|&s| s as usize).sum::<usize>();
+
+            let mut page = bytes[..length].to_vec();
+            let crc = u32::from_le_bytes(page[22..26].try_into().unwrap());
+            page[22..26].fill(0);
+            assert_eq!(ogg_crc32(0, &page), crc, "checksum of page {}", pages.len());
+
+            pages.push(Page {
+                header_type: bytes[5],
+                granule: u64::from_le_bytes(bytes[6..14].try_into().unwrap()),
+                sequence: u32::from_le_bytes(bytes[18..22].try_into().unwrap()),
+                segments,
+                data: bytes[27 + num_segments..length].to_vec(),
+            });
+            bytes = &bytes[length..];
+        }
+        pages
+    }
+
+    /// Joins the segments of all pages back into packets
+    fn read_packets(pages: &[Page]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut packet = Vec::new();
+        for page in pages {
+            assert_eq!(
+                page.header_type & HEADER_TYPE_CONTINUED != 0,
+                !packet.is_empty()
+            );
+            let mut data = &page.data[..];
+            for &segment in &page.segments {
+                packet.extend_from_slice(&data[..segment as usize]);
+                data = &data[segment as usize..];
+                if segment < 255 {
+                    packets.push(core::mem::take(&mut packet));
+                }
+            }
+        }
+        assert!(packet.is_empty(), "unterminated packet");
+        packets
+    }
+
+    #[test]
+    fn matches_libogg_page() {
+        // head1_0 from libogg's src/test_framing.c, one 17 byte packet on a BOS and EOS page
+        let expected = [
+            0x4f, 0x67, 0x67, 0x53, 0, 0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0x02, 0x03, 0x04, 0, 0,
+            0, 0, 0x15, 0xed, 0xec, 0x91, 1, 17, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14,
+            15, 16,
+        ];
+        let packet: Vec<u8> = (0..17).collect();
+
+        let mut writer = OggPageWriter::new(Vec::new(), 0x0403_0201);
+        writer.write_packet(&packet, 0).unwrap();
+        assert_eq!(writer.finish().unwrap(), expected);
+    }
+
+    #[test]
+    fn long_packet_spans_pages() {
+        // 255 full segments fill a page, so the terminating empty segment goes on a second one
+        let long: Vec<u8> = (0..255 * 255).map(|i| i as u8).collect();
+        let short = [7u8; 10];
+
+        let mut writer = OggPageWriter::new(Vec::new(), 1);
+        writer.write_packet(&long, 100).unwrap();
+        writer.write_packet(&short, 200).unwrap();
+        let pages = read_pages(&writer.finish().unwrap());
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].header_type, HEADER_TYPE_BOS);
+        assert_eq!(pages[0].granule, NO_GRANULE);
+        assert_eq!(pages[0].segments, [255; 255]);
+        assert_eq!(
+            pages[1].header_type,
+            HEADER_TYPE_CONTINUED | HEADER_TYPE_EOS
+        );
+        assert_eq!(pages[1].granule, 200);
+        assert_eq!(pages[1].segments, [0, 10]);
+        assert_eq!(
+            pages.iter().map(|page| page.sequence).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        assert_eq!(read_packets(&pages), [long, short.to_vec()]);
+    }
+
+    #[test]
+    fn eos_goes_on_last_data_page() {
+        let mut writer = OggPageWriter::new(Vec::new(), 1);
+        writer.write_packet(&[1; 10], 0).unwrap();
+        writer.flush_page().unwrap();
+        writer.write_packet(&[2; 10], 300).unwrap();
+        writer.flush_page().unwrap();
+        let pages = read_pages(&writer.finish().unwrap());
+
+        assert_eq!(pages.len(), 2);
+        assert_eq!(pages[0].header_type, HEADER_TYPE_BOS);
+        assert_eq!(pages[1].header_type, HEADER_TYPE_EOS);
+        assert_eq!(pages[1].granule, 300);
+        assert_eq!(pages[1].segments, [10]);
+    }
+
+    #[test]
+    fn empty_stream_gets_one_page() {
+        let pages = read_pages(&OggPageWriter::new(Vec::new(), 1).finish().unwrap());
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].header_type, HEADER_TYPE_BOS | HEADER_TYPE_EOS);
+        assert_eq!(pages[0].granule, 0);
+        assert!(pages[0].segments.is_empty());
+    }
+
+    #[test]
+    fn flac_mapping() {
+        let stream_info = MetadataBlockStreamInfo {
+            min_block_size: 4096,
+            max_block_size: 4096,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            sample_rate: 44100,
+            num_channels: 2,
+            sample_depth: 16,
+            num_samples: 10000,
+            decoded_checksum: 0,
+        };
+        let frames = [vec![1u8; 3000], vec![2u8; 3000], vec![3u8; 500]];
+        let block_sizes = [4096, 4096, 1808];
+
+        let mut writer = OggFlacWriter::new(Vec::new(), 42, &stream_info, &[]).unwrap();
+        for (frame, block_size) in frames.iter().zip(block_sizes) {
+            writer.write_frame(frame, block_size).unwrap();
+        }
+        let pages = read_pages(&writer.finish().unwrap());
+        let packets = read_packets(&pages);
+
+        // The first page holds only the identification packet, the comment gets a page of its own
+        assert_eq!(pages[0].header_type, HEADER_TYPE_BOS);
+        assert_eq!(pages[0].segments, [51]);
+        assert_eq!(&pages[0].data[..14], b"\x7fFLAC\x01\x00\x00\x01fLaC\x00");
+        assert_eq!(pages[1].header_type, 0);
+        assert_eq!(packets[1][0], 0x84); // last block, VORBIS_COMMENT
+
+        let last = pages.last().unwrap();
+        assert_eq!(last.header_type & HEADER_TYPE_EOS, HEADER_TYPE_EOS);
+        assert_eq!(last.granule, 10000);
+        assert!(pages[..pages.len() - 1]
+            .iter()
+            .all(|page| page.header_type & HEADER_TYPE_EOS == 0));
+        assert!(pages[1..]
+            .iter()
+            .all(|page| page.header_type & HEADER_TYPE_BOS == 0));
+        assert_eq!(&packets[2..], frames);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::bitstream::{BitstreamReader, BufferedBitstreamReader};
+use crate::block_parser::{read_magic, read_metadata_blocks};
+use crate::crc::crc16;
+use crate::decoder::DecodedFrame;
+use crate::error::{Context, Error, Position};
+use crate::frame_parser::{is_frame_header, read_frame};
+use crate::frame_types::Frame;
+use crate::limits::Limits;
+use crate::metadata_types::{MetadataBlock, MetadataBlockStreamInfo};
+use crate::position::PositionTracker;
+use crate::sample_decoder::decode_frame;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+const MAX_HEADER_LENGTH: usize = 16;
+const MAX_FRAME_SIZE: usize = 8 * 65536 * 5; // Above 8 channels of 65536 verbatim 33-bit samples
+const FRAMES_PER_THREAD: usize = 4; // Frames in flight for each worker
+
+/// The bytes of one frame, as found by searching for frame headers
+struct Job {
+    index: u64,
+    offset: usize, // In bytes from the start of the stream
+    bytes: Vec<u8>,
+}
+
+type FrameResult = Result<(Frame, Box<[Box<[i32]>]>), Error>;
+type Output = (u64, FrameResult);
+
+/// Decodes the frames of a native FLAC stream on several threads, returning them in order. One
+/// thread cuts the stream into frames by searching for frame headers, the others decode them.
+pub struct ParallelDecoder {
+    stream_info: MetadataBlockStreamInfo,
+    metadata: Vec<MetadataBlock>,
+    positions: PositionTracker,
+    num_frames: u64,
+    results: Receiver<Output>,
+    num_jobs: Arc<AtomicU64>, // Frames handed to the workers so far
+    pending: BTreeMap<u64, FrameResult>, // Decoded out of order
+    credits: SyncSender<()>,  // Returned for each frame handed out, limits the frames in flight
+}
+
+impl ParallelDecoder {
+    pub fn open(filename: &Path, num_threads: usize) -> Result<Self, Error> {
+        Self::new(File::open(filename)?, num_threads)
+    }
+
+    /// Reads the metadata on the calling thread and starts `num_threads` workers for the frames
+    pub fn new<R: Read + Send + 'static>(reader: R, num_threads: usize) -> Result<Self, Error> {
+        Self::with_limits(reader, num_threads, Limits::DEFAULT)
+    }
+
+    pub fn with_limits<R: Read + Send + 'static>(
+        reader: R,
+        num_threads: usize,
+        limits: Limits,
+    ) -> Result<Self, Error> {
+        let mut reader = BufReader::new(reader);
+        let mut header_reader = BufferedBitstreamReader::new(&mut reader);
+        header_reader.set_limits(limits);
+        read_magic(&mut header_reader)?;
+        let (stream_info, metadata) = read_metadata_blocks(&mut header_reader)?;
+        let offset = header_reader.get_total_position() / 8;
+
+        let num_threads = num_threads.max(1);
+        let max_in_flight = num_threads * FRAMES_PER_THREAD;
+        let (credits, credits_receiver) = sync_channel(max_in_flight);
+        for _ in 0..max_in_flight {
+            credits.send(()).unwrap();
+        }
+
+        let (jobs, jobs_receiver) = channel();
+        let (results_sender, results) = channel();
+        let jobs_receiver = Arc::new(Mutex::new(jobs_receiver));
+        for _ in 0..num_threads {
+            let jobs = jobs_receiver.clone();
+            let results = results_sender.clone();
+            let stream_info = stream_info.clone();
+            thread::spawn(move || decode_frames(&jobs, &results, &stream_info, limits));
+        }
+
+        let max_frame_size = match stream_info.max_frame_size {
+            0 => MAX_FRAME_SIZE,
+            size => size as usize,
+        };
+        let num_jobs = Arc::new(AtomicU64::new(0));
+        let splitter_num_jobs = num_jobs.clone();
+        thread::spawn(move || {
+            split_frames(
+                reader,
+                offset,
+                max_frame_size,
+                &jobs,
+                &results_sender,
+                &credits_receiver,
+                &splitter_num_jobs,
+            )
+        });
+
+        Ok(ParallelDecoder {
+            positions: PositionTracker::new(&stream_info),
+            stream_info,
+            metadata,
+            num_frames: 0,
+            results,
+            num_jobs,
+            pending: BTreeMap::new(),
+            credits,
+        })
+    }
+
+    pub fn stream_info(&self) -> &MetadataBlockStreamInfo {
+        &self.stream_info
+    }
+
+    /// All metadata blocks, starting with STREAMINFO
+    pub fn metadata(&self) -> &[MetadataBlock] {
+        &self.metadata
+    }
+
+    /// Returns the next frame, or `None` at the end of the stream. An error only concerns the
+    /// frame it is returned for, the frames after it can still be decoded.
+    pub fn next_frame(&mut self) -> Result<Option<DecodedFrame>, Error> {
+        let result = loop {
+            if let Some(result) = self.pending.remove(&self.num_frames) {
+                break result;
+            }
+            match self.results.recv() {
+                Ok((index, result)) => {
+                    self.pending.insert(index, result);
+                }
+                // All threads are done, but a frame handed out never came back
+                Err(_)
+                    if self.num_frames < self.num_jobs.load(Ordering::Acquire)
+                        || !self.pending.is_empty() =>
+                {
+                    break Err(Error::missing(Context::Frame(self.num_frames), None));
+                }
+                Err(_) => return Ok(None),
+            }
+        };
+        self.num_frames += 1;
+        // Fails once the stream has been cut up completely
+        let _ = self.credits.try_send(());
+
+        let (frame, samples) = result?;
+        let position = self.positions.track(&frame);
+        Ok(Some(DecodedFrame {
+            frame,
+            position,
+            samples,
+            lost: None,
+        }))
+    }
+}
+
+/// Hands out the stream after the metadata frame by frame. A frame ends at the next frame header
+/// that its CRC-16 matches up to, or at any frame header once it is too long to be valid.
+fn split_frames(
+    mut reader: impl BufRead,
+    mut offset: usize,
+    max_frame_size: usize,
+    jobs: &Sender<Job>,
+    results: &Sender<Output>,
+    credits: &Receiver<()>,
+    num_jobs: &AtomicU64,
+) {
+    let mut data = Vec::new();
+    let mut scanned = 0; // Length of the frame so far, which `crc` covers
+    let mut crc = 0;
+    let mut at_end = false;
+    let mut index = 0;
+    loop {
+        // Keep enough bytes after the frame to check for a header
+        if !at_end && data.len() < scanned + MAX_HEADER_LENGTH {
+            match reader.fill_buf() {
+                Ok([]) => at_end = true,
+                Ok(buf) => {
+                    let length = buf.len();
+                    data.extend_from_slice(buf);
+                    reader.consume(length);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    let _ = results.send((index, Err(e.into())));
+                    return;
+                }
+            }
+            continue;
+        }
+
+        let is_end = scanned == data.len();
+        // Damaged data without any frame headers is handed over in pieces
+        let is_boundary = is_end
+            || scanned >= 2 * max_frame_size
+            || scanned > 0
+                && (crc == 0 || scanned >= max_frame_size)
+                && is_frame_header(&data[scanned..]);
+        if is_boundary && scanned > 0 {
+            if credits.recv().is_err() {
+                return;
+            }
+            let bytes = data.drain(..scanned).collect();
+            let job = Job {
+                index,
+                offset,
+                bytes,
+            };
+            if jobs.send(job).is_err() {
+                return;
+            }
+            index += 1;
+            num_jobs.store(index, Ordering::Release);
+            offset += scanned;
+            scanned = 0;
+            crc = 0;
+        }
+        if is_end {
+            return;
+        }
+
+        crc = crc16(crc, &data[scanned..scanned + 1]);
+        scanned += 1;
+    }
+}
+
+fn decode_frames(
+    jobs: &Mutex<Receiver<Job>>,
+    results: &Sender<Output>,
+    stream_info: &MetadataBlockStreamInfo,
+    limits: Limits,
+) {
+    loop {
+        let job = match jobs.lock().unwrap_or_else(PoisonError::into_inner).recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+
+        let start = Position::from_bits(8 * job.offset);
+        let mut reader = BufferedBitstreamReader::new(&job.bytes[..]).starting_at(8 * job.offset);
+        reader.set_limits(limits);
+        // A panic only loses its own frame instead of the rest of the stream
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let frame = read_frame(&mut reader, stream_info)?;
+            let samples = decode_frame(&frame);
+            Ok((frame, samples))
+        }))
+        .unwrap_or_else(|payload| Err(Error::Panicked(panic_message(payload))))
+        .map_err(|e| e.within(Context::Frame(job.index), start));
+        if results.send((job.index, result)).is_err() {
+            return;
+        }
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_info() -> MetadataBlockStreamInfo {
+        MetadataBlockStreamInfo {
+            min_block_size: 192,
+            max_block_size: 192,
+            min_frame_size: 0,
+            max_frame_size: 0,
+            sample_rate: 44100,
+            num_channels: 1,
+            sample_depth: 16,
+            num_samples: 0,
+            decoded_checksum: 0,
+        }
+    }
+
+    /// A decoder whose workers have finished after returning `outputs` out of `num_jobs` frames
+    fn finished_decoder(outputs: Vec<Output>, num_jobs: u64) -> ParallelDecoder {
+        let (sender, results) = channel();
+        for output in outputs {
+            sender.send(output).unwrap();
+        }
+        let (credits, _) = sync_channel(1);
+        let stream_info = stream_info();
+        ParallelDecoder {
+            positions: PositionTracker::new(&stream_info),
+            stream_info,
+            metadata: Vec::new(),
+            num_frames: 0,
+            results,
+            num_jobs: Arc::new(AtomicU64::new(num_jobs)),
+            pending: BTreeMap::new(),
+            credits,
+        }
+    }
+
+    fn is_missing_frame(result: Result<Option<DecodedFrame>, Error>, idx: u64) -> bool {
+        matches!(result, Err(Error::Missing { context: Context::Frame(i), .. }) if i == idx)
+    }
+
+    #[test]
+    fn missing_frame_in_the_middle() {
+        let mut decoder = finished_decoder(
+            vec![
+                (0, Err(Error::UnexpectedEof)),
+                (2, Err(Error::UnexpectedEof)),
+            ],
+            3,
+        );
+        assert!(matches!(decoder.next_frame(), Err(Error::UnexpectedEof)));
+        assert!(is_missing_frame(decoder.next_frame(), 1));
+        assert!(matches!(decoder.next_frame(), Err(Error::UnexpectedEof)));
+        assert!(matches!(decoder.next_frame(), Ok(None)));
+    }
+
+    #[test]
+    fn missing_frames_at_the_end() {
+        let mut decoder = finished_decoder(vec![(0, Err(Error::UnexpectedEof))], 2);
+        assert!(matches!(decoder.next_frame(), Err(Error::UnexpectedEof)));
+        assert!(is_missing_frame(decoder.next_frame(), 1));
+        assert!(matches!(decoder.next_frame(), Ok(None)));
+    }
+
+    #[test]
+    fn poisoned_lock_does_not_stop_workers() {
+        let (jobs, jobs_receiver) = channel();
+        let (results, results_receiver) = channel();
+        jobs.send(Job {
+            index: 0,
+            offset: 0,
+            bytes: Vec::new(),
+        })
+        .unwrap();
+        drop(jobs);
+        let jobs_receiver = Mutex::new(jobs_receiver);
+        // A lock poisoned by another worker does not stop this one
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = jobs_receiver.lock().unwrap();
+            panic!("poison");
+        }));
+        decode_frames(&jobs_receiver, &results, &stream_info(), Limits::DEFAULT);
+        assert!(matches!(results_receiver.try_recv(), Ok((0, Err(_)))));
+    }
+
+    #[test]
+    fn panic_messages() {
+        let message = |f: fn()| panic_message(panic::catch_unwind(f).unwrap_err());
+        assert_eq!(message(|| panic!("static")), "static");
+        assert_eq!(message(|| panic!("{}", 5)), "5");
+        assert_eq!(message(|| panic::panic_any(5)), "unknown panic");
+    }
+}
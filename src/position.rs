@@ -0,0 +1,107 @@
+use crate::frame_types::{Frame, FrameOrSampleNumber};
+use crate::metadata_types::MetadataBlockStreamInfo;
+use core::time::Duration;
+
+/// How a frame relates to the frames before it
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Continuity {
+    /// The frame starts where the previous one ended (or is the first frame at sample 0)
+    Contiguous,
+    /// Samples between the end of the previous frame and this one are missing
+    Gap { expected: u64, found: u64 },
+    /// The frame starts at the same sample as the previous frame
+    Duplicate { first_sample: u64 },
+    /// The frame starts before the end of the previous frame
+    OutOfOrder { expected: u64, found: u64 },
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct FramePosition {
+    pub first_sample: u64,
+    pub timestamp: Duration,
+    pub continuity: Continuity,
+}
+
+/// Converts frame headers into absolute sample positions and checks that no frames are missing.
+/// Frames of variable-blocksize streams carry their first sample, the others their index.
+#[derive(Debug, Clone)]
+pub struct PositionTracker {
+    fixed_block_size: Option<u64>, // Block size of frames numbered by index, once known
+    expected_sample: u64,
+    previous_sample: Option<u64>,
+}
+
+impl PositionTracker {
+    pub fn new(stream_info: &MetadataBlockStreamInfo) -> Self {
+        // Otherwise the block size is learned from the first frame numbered by index
+        let fixed_block_size = match (stream_info.min_block_size, stream_info.max_block_size) {
+            (min, max) if min == max && max > 0 => Some(max as u64),
+            _ => None,
+        };
+
+        PositionTracker {
+            fixed_block_size,
+            expected_sample: 0,
+            previous_sample: None,
+        }
+    }
+
+    /// Absolute index of the first sample in the frame
+    pub fn first_sample(&self, frame: &Frame) -> u64 {
+        match frame.frame_or_sample_number {
+            FrameOrSampleNumber::Frame(number) => {
+                let block_size = self.fixed_block_size.unwrap_or(frame.block_size as u64);
+                number.0 as u64 * block_size
+            }
+            FrameOrSampleNumber::Sample(number) => number.0,
+        }
+    }
+
+    /// Index of the sample the next frame should start at
+    pub fn expected_sample(&self) -> u64 {
+        self.expected_sample
+    }
+
+    pub fn track(&mut self, frame: &Frame) -> FramePosition {
+        let first_sample = self.first_sample(frame);
+        let expected = self.expected_sample;
+
+        let continuity = if first_sample == expected {
+            Continuity::Contiguous
+        } else if first_sample > expected {
+            Continuity::Gap {
+                expected,
+                found: first_sample,
+            }
+        } else if self.previous_sample == Some(first_sample) {
+            Continuity::Duplicate { first_sample }
+        } else {
+            Continuity::OutOfOrder {
+                expected,
+                found: first_sample,
+            }
+        };
+
+        if let FrameOrSampleNumber::Frame(_) = frame.frame_or_sample_number {
+            self.fixed_block_size.get_or_insert(frame.block_size as u64);
+        }
+        self.previous_sample = Some(first_sample);
+        self.expected_sample = first_sample + frame.block_size as u64;
+
+        FramePosition {
+            first_sample,
+            timestamp: sample_timestamp(first_sample, frame.sample_rate),
+            continuity,
+        }
+    }
+}
+
+/// Time at which `sample` is played at the given sample rate
+pub fn sample_timestamp(sample: u64, sample_rate: u32) -> Duration {
+    if sample_rate == 0 {
+        return Duration::from_secs(0);
+    }
+    let sample_rate = sample_rate as u64;
+    let nanos = (sample % sample_rate) * 1_000_000_000 / sample_rate;
+    Duration::new(sample / sample_rate, nanos as u32)
+}
@@ -0,0 +1,270 @@
+use crate::bitstream::{BitstreamReader, BufferedBitstreamReader};
+use crate::block_parser::{read_magic, read_nth_metadata_block};
+use crate::crc::crc16;
+use crate::decoder::DecodedFrame;
+use crate::error::{Context, Error, Position};
+use crate::frame_parser::read_frame;
+use crate::limits::Limits;
+use crate::metadata_types::{MetadataBlock, MetadataBlockData, MetadataBlockStreamInfo};
+use crate::position::PositionTracker;
+use crate::sample_decoder::decode_frame;
+use alloc::vec::Vec;
+
+/// What a `PushDecoder` has found in the bytes fed to it
+#[derive(Debug, Clone)]
+pub enum PushEvent {
+    /// A metadata block, STREAMINFO first
+    Metadata(MetadataBlock),
+    Frame(DecodedFrame),
+    /// Everything complete has been parsed, feed more bytes to continue
+    NeedMoreData,
+    /// `finish` has been called and the whole stream has been parsed
+    End,
+}
+
+const MAX_UNKNOWN_FRAME_SIZE: usize = 64 * 1024; // Retry interval when STREAMINFO has no size
+
+/// Decodes a native FLAC stream from byte chunks of any size. Anything cut off at the end of the
+/// bytes fed so far is parsed again once it can be complete: metadata blocks once their whole
+/// length has arrived, frames once the bytes scanned so far end in a matching CRC-16.
+pub struct PushDecoder {
+    buffer: Vec<u8>,
+    consumed: usize, // Bytes at the start of `buffer` that have been parsed
+    offset: usize,   // Position of `buffer[0]` in the stream, in bytes
+    needed: usize,   // Unparsed bytes the next metadata block takes up
+    scanned: usize,  // Unparsed bytes of the next frame covered by `crc`
+    crc: u16,
+    retry_at: usize, // Scanned length at which to parse again even without a CRC-16 match
+    magic_read: bool,
+    metadata: Vec<MetadataBlock>,
+    metadata_size: usize, // In bytes
+    stream_info: Option<MetadataBlockStreamInfo>,
+    positions: Option<PositionTracker>,
+    num_frames: u64,
+    finished: bool,
+    limits: Limits,
+}
+
+impl Default for PushDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PushDecoder {
+    pub fn new() -> Self {
+        PushDecoder {
+            buffer: Vec::new(),
+            consumed: 0,
+            offset: 0,
+            needed: 0,
+            scanned: 0,
+            crc: 0,
+            retry_at: 0,
+            magic_read: false,
+            metadata: Vec::new(),
+            metadata_size: 0,
+            stream_info: None,
+            positions: None,
+            num_frames: 0,
+            finished: false,
+            limits: Limits::DEFAULT,
+        }
+    }
+
+    /// Sets what the parsers accept, before feeding the first bytes
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        // Drop parsed bytes once they make up most of the buffer
+        if self.consumed > self.buffer.len() / 2 {
+            self.buffer.drain(..self.consumed);
+            self.offset += self.consumed;
+            self.consumed = 0;
+        }
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Marks the end of the input, after which a stream cut off in the middle is an error instead
+    /// of a request for more data
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Number of bytes fed but not yet parsed
+    pub fn buffered(&self) -> usize {
+        self.buffer.len() - self.consumed
+    }
+
+    /// Available once the STREAMINFO block has been parsed
+    pub fn stream_info(&self) -> Option<&MetadataBlockStreamInfo> {
+        self.stream_info.as_ref()
+    }
+
+    /// The metadata blocks parsed so far, starting with STREAMINFO
+    pub fn metadata(&self) -> &[MetadataBlock] {
+        &self.metadata
+    }
+
+    /// Whether all metadata blocks have been parsed
+    pub fn is_header_complete(&self) -> bool {
+        self.metadata.last().is_some_and(|block| block.is_last)
+    }
+
+    /// Parses as much as possible of the bytes fed so far
+    pub fn next_event(&mut self) -> Result<PushEvent, Error> {
+        if !self.is_header_complete() {
+            return Ok(match self.next_metadata_block()? {
+                Some(block) => PushEvent::Metadata(block),
+                None => self.incomplete(),
+            });
+        }
+
+        Ok(match self.next_frame()? {
+            Some(frame) => PushEvent::Frame(frame),
+            None => self.incomplete(),
+        })
+    }
+
+    fn incomplete(&self) -> PushEvent {
+        if self.finished {
+            PushEvent::End
+        } else {
+            PushEvent::NeedMoreData
+        }
+    }
+
+    /// Parses the remaining metadata blocks, returns false if more bytes are needed
+    pub(crate) fn read_header(&mut self) -> Result<bool, Error> {
+        while !self.is_header_complete() {
+            if self.next_metadata_block()?.is_none() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn next_metadata_block(&mut self) -> Result<Option<MetadataBlock>, Error> {
+        if self.buffered() < self.needed && !self.finished {
+            return Ok(None);
+        }
+        if !self.magic_read {
+            if self.parse(read_magic)?.is_none() {
+                return Ok(None);
+            }
+            self.magic_read = true;
+        }
+
+        let idx = self.metadata.len();
+        let mut metadata_size = self.metadata_size;
+        let block =
+            match self.parse(|reader| read_nth_metadata_block(reader, idx, &mut metadata_size))? {
+                Some(block) => block,
+                None => {
+                    // Wait for the whole block, its length is in the last 3 bytes of its header
+                    let unparsed = &self.buffer[self.consumed..];
+                    self.needed = match unparsed.get(1..4) {
+                        Some(length) => {
+                            4 + u32::from_be_bytes([0, length[0], length[1], length[2]]) as usize
+                        }
+                        None => 4,
+                    };
+                    return Ok(None);
+                }
+            };
+        self.needed = 0;
+        self.metadata_size = metadata_size;
+        if let MetadataBlockData::StreamInfo(stream_info) = &block.content {
+            self.positions = Some(PositionTracker::new(stream_info));
+            self.stream_info = Some(stream_info.clone());
+        }
+        self.metadata.push(block.clone());
+        Ok(Some(block))
+    }
+
+    /// Parses and decodes the next frame, returns None if more bytes are needed or, after
+    /// `finish`, at the end of the stream
+    pub(crate) fn next_frame(&mut self) -> Result<Option<DecodedFrame>, Error> {
+        if !self.read_header()? || self.buffered() == 0 {
+            return Ok(None);
+        }
+
+        let stream_info = self.stream_info.clone().unwrap();
+        if self.scanned == 0 {
+            self.retry_at = match stream_info.max_frame_size {
+                0 => MAX_UNKNOWN_FRAME_SIZE,
+                size => size as usize,
+            };
+        }
+        if !self.finished && !self.scan_for_frame_end() {
+            return Ok(None);
+        }
+
+        let context = Context::Frame(self.num_frames);
+        let start = Position::from_bits(8 * (self.offset + self.consumed));
+        let frame = match self.parse(|reader| {
+            read_frame(reader, &stream_info).map_err(|e| e.within(context, start))
+        })? {
+            Some(frame) => frame,
+            None => {
+                // Damaged data may never match, so the bytes to scan before trying again double
+                if self.scanned >= self.retry_at {
+                    self.retry_at = self.scanned.saturating_mul(2);
+                }
+                return Ok(None);
+            }
+        };
+        self.scanned = 0;
+        self.crc = 0;
+        self.num_frames += 1;
+
+        let position = self.positions.as_mut().unwrap().track(&frame);
+        let samples = decode_frame(&frame);
+        Ok(Some(DecodedFrame {
+            frame,
+            position,
+            samples,
+            lost: None,
+        }))
+    }
+
+    /// Scans the unparsed bytes for the end of the next frame. A frame ends with the CRC-16 of
+    /// everything before it, so the CRC-16 up to the end of a complete frame is 0. Returns true
+    /// at such a place, or once `retry_at` bytes have been scanned.
+    fn scan_for_frame_end(&mut self) -> bool {
+        let unparsed = &self.buffer[self.consumed..];
+        while self.scanned < unparsed.len() {
+            self.crc = crc16(self.crc, &unparsed[self.scanned..self.scanned + 1]);
+            self.scanned += 1;
+            if self.crc == 0 || self.scanned >= self.retry_at {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Runs `parse` on the unparsed bytes, returning None if they end too early
+    fn parse<T>(
+        &mut self,
+        parse: impl FnOnce(&mut dyn BitstreamReader) -> Result<T, Error>,
+    ) -> Result<Option<T>, Error> {
+        let start = self.offset + self.consumed;
+        let mut reader =
+            BufferedBitstreamReader::new(&self.buffer[self.consumed..]).starting_at(8 * start);
+        reader.set_limits(self.limits);
+        match parse(&mut reader) {
+            Ok(value) => {
+                self.consumed += reader.get_total_position() / 8 - start;
+                Ok(Some(value))
+            }
+            Err(e) if e.is_eof() && !self.finished => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -1,17 +1,24 @@
-use crate::error::Error;
+use crate::error::{Context, Error, Position};
 use crate::bitstream::BitstreamReader;
 
+/// Reads one Rice coded residual, which has to fit into 32 bits
 pub(crate) fn read_rice(
     reader: &mut dyn BitstreamReader,
     encoding_parameter: u8
 ) -> Result<i32, Error> {
     let quotient = reader.read_unary(false)?;
-    let remainder = reader.read_unsigned(encoding_parameter)? as u32;
-    let raw = (quotient << encoding_parameter as u32) | remainder;
+    let remainder = reader.read_unsigned(encoding_parameter)? as u64;
+    let raw = (quotient as u64) << encoding_parameter as u32 | remainder;
+    if raw > u32::MAX as u64 {
+        let length = quotient as usize + 1 + encoding_parameter as usize;
+        return Err(Error::invalid(Context::Residual, Position::before(reader, length), raw));
+    }
+
+    let raw = raw as u32;
     let is_negative = raw % 2 == 1;
     Ok(if is_negative {
         -((raw / 2) as i32) - 1
     } else {
         (raw / 2) as i32
     })
-}
\ No newline at end of file
+}
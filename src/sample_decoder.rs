@@ -0,0 +1,212 @@
+use crate::frame_types::{ChannelAssignment, Frame, Residual, Subframe, SubframeData};
+use crate::lpc;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const FIXED_COEFFICIENTS: [&[i32]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+/// Reconstructs the samples of every channel of a frame
+pub fn decode_frame(frame: &Frame) -> Box<[Box<[i32]>]> {
+    let side_channel = frame.channel_assignment.side_channel();
+    let mut wide_side = None;
+    let mut channels: Vec<Vec<i32>> = frame
+        .subframes
+        .iter()
+        .enumerate()
+        .map(|(channel, subframe)| {
+            let depth = frame.sample_depth + (side_channel == Some(channel)) as u8;
+            if depth > 32 {
+                wide_side = Some(decode_wide_subframe(subframe, frame.block_size));
+                Vec::new()
+            } else {
+                decode_subframe(subframe, depth, frame.block_size)
+            }
+        })
+        .collect();
+
+    match wide_side {
+        Some(side) => decorrelate_wide(frame.channel_assignment, &mut channels, &side),
+        None => decorrelate(frame.channel_assignment, &mut channels),
+    }
+
+    channels.into_iter().map(Vec::into_boxed_slice).collect()
+}
+
+/// Reconstructs the samples of one subframe of up to 32 bits, before stereo decorrelation.
+/// `sample_depth` includes the extra bit of a side channel.
+pub fn decode_subframe(subframe: &Subframe, sample_depth: u8, block_size: u32) -> Vec<i32> {
+    let sample_depth = sample_depth.saturating_sub(subframe.wasted_bits);
+    let mut samples = match &subframe.data {
+        SubframeData::Constant(constant) => vec![constant.content as i32; block_size as usize],
+        SubframeData::Verbatim(verbatim) => verbatim.content.iter().map(|s| *s as i32).collect(),
+        SubframeData::Fixed(fixed) => restore_lpc(
+            &fixed.warmup,
+            &fixed.residual,
+            FIXED_COEFFICIENTS[fixed.order as usize],
+            0,
+            sample_depth,
+        ),
+        SubframeData::LPC(lpc) => {
+            let coefficients: Vec<i32> = lpc.coefficients.iter().map(|c| *c as i32).collect();
+            restore_lpc(
+                &lpc.warmup,
+                &lpc.residual,
+                &coefficients,
+                lpc.shift as u32,
+                sample_depth,
+            )
+        }
+        SubframeData::Reserved => vec![0; block_size as usize],
+    };
+
+    if subframe.wasted_bits > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= subframe.wasted_bits;
+        }
+    }
+    samples
+}
+
+/// Reconstructs the samples of the 33-bit side channel of 32-bit audio, which only fit into 32
+/// bits again after stereo decorrelation
+pub fn decode_wide_subframe(subframe: &Subframe, block_size: u32) -> Vec<i64> {
+    let mut samples = match &subframe.data {
+        SubframeData::Constant(constant) => vec![constant.content; block_size as usize],
+        SubframeData::Verbatim(verbatim) => verbatim.content.to_vec(),
+        SubframeData::Fixed(fixed) => restore_wide_lpc(
+            &fixed.warmup,
+            &fixed.residual,
+            FIXED_COEFFICIENTS[fixed.order as usize],
+            0,
+        ),
+        SubframeData::LPC(lpc) => {
+            let coefficients: Vec<i32> = lpc.coefficients.iter().map(|c| *c as i32).collect();
+            restore_wide_lpc(&lpc.warmup, &lpc.residual, &coefficients, lpc.shift as u32)
+        }
+        SubframeData::Reserved => vec![0; block_size as usize],
+    };
+
+    if subframe.wasted_bits > 0 {
+        for sample in samples.iter_mut() {
+            *sample <<= subframe.wasted_bits;
+        }
+    }
+    samples
+}
+
+/// Runs the prediction filter over the residual, `coefficients[0]` applies to the most
+/// recent sample
+fn restore_lpc(
+    warmup: &[i64],
+    residual: &Residual,
+    coefficients: &[i32],
+    shift: u32,
+    sample_depth: u8,
+) -> Vec<i32> {
+    let mut samples = Vec::with_capacity(warmup.len() + residual_len(residual));
+    samples.extend(warmup.iter().map(|s| *s as i32));
+    for partition in residual.partitions.iter() {
+        samples.extend_from_slice(&partition.residual);
+    }
+
+    let wide = lpc::needs_wide(coefficients, sample_depth);
+    lpc::restore(&mut samples, coefficients, shift, wide);
+    samples
+}
+
+fn restore_wide_lpc(
+    warmup: &[i64],
+    residual: &Residual,
+    coefficients: &[i32],
+    shift: u32,
+) -> Vec<i64> {
+    let mut samples = Vec::with_capacity(warmup.len() + residual_len(residual));
+    samples.extend_from_slice(warmup);
+    for partition in residual.partitions.iter() {
+        samples.extend(partition.residual.iter().map(|r| *r as i64));
+    }
+
+    lpc::restore_wide_samples(&mut samples, coefficients, shift);
+    samples
+}
+
+fn residual_len(residual: &Residual) -> usize {
+    residual.partitions.iter().map(|p| p.residual.len()).sum()
+}
+
+fn decorrelate(channel_assignment: ChannelAssignment, channels: &mut [Vec<i32>]) {
+    let (first, second) = match channels {
+        [first, second] => (first, second),
+        _ => return,
+    };
+
+    match channel_assignment {
+        ChannelAssignment::Direct => {}
+        ChannelAssignment::LeftSide => {
+            for (left, side) in first.iter().zip(second.iter_mut()) {
+                *side = left.wrapping_sub(*side);
+            }
+        }
+        ChannelAssignment::RightSide => {
+            for (side, right) in first.iter_mut().zip(second.iter()) {
+                *side = side.wrapping_add(*right);
+            }
+        }
+        ChannelAssignment::MidSide => {
+            for (mid, side) in first.iter_mut().zip(second.iter_mut()) {
+                let (left, right) = mid_side_to_left_right(*mid, *side as i64);
+                *mid = left;
+                *side = right;
+            }
+        }
+    }
+}
+
+/// Like `decorrelate`, with the side channel passed separately because it needs 33 bits
+fn decorrelate_wide(
+    channel_assignment: ChannelAssignment,
+    channels: &mut [Vec<i32>],
+    side: &[i64],
+) {
+    let (first, second) = match channels {
+        [first, second] => (first, second),
+        _ => return,
+    };
+
+    match channel_assignment {
+        ChannelAssignment::Direct => {}
+        ChannelAssignment::LeftSide => {
+            *second = first
+                .iter()
+                .zip(side.iter())
+                .map(|(left, side)| (*left as i64 - side) as i32)
+                .collect();
+        }
+        ChannelAssignment::RightSide => {
+            *first = side
+                .iter()
+                .zip(second.iter())
+                .map(|(side, right)| (side + *right as i64) as i32)
+                .collect();
+        }
+        ChannelAssignment::MidSide => {
+            *second = first
+                .iter_mut()
+                .zip(side.iter())
+                .map(|(mid, side)| {
+                    let (left, right) = mid_side_to_left_right(*mid, *side);
+                    *mid = left;
+                    right
+                })
+                .collect();
+        }
+    }
+}
+
+fn mid_side_to_left_right(mid: i32, side: i64) -> (i32, i32) {
+    let full_mid = ((mid as i64) << 1) | (side & 1);
+    let left = (full_mid + side) >> 1;
+    let right = (full_mid - side) >> 1;
+    (left as i32, right as i32)
+}
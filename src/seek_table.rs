@@ -0,0 +1,272 @@
+use crate::bitstream::{
+    BitstreamReader, BitstreamWriter, BufferedBitstreamReader, BufferedBitstreamWriter,
+};
+use crate::block_parser::read_magic;
+use crate::block_writer::{write_magic, write_metadata_block};
+use crate::error::Error;
+use crate::frame_index::{FrameIndex, FrameIndexEntry};
+use crate::metadata_types::{MetadataBlock, MetadataBlockData, MetadataBlockSeekTable, SeekPoint};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Duration;
+
+/// Most seek points a SEEKTABLE fits, its length field is 24 bits and each point takes 18 bytes
+const MAX_SEEK_POINTS: usize = ((1 << 24) - 1) / 18;
+
+/// Where seek points are placed
+#[derive(Debug, Clone)]
+pub enum SeekSpacing {
+    Time(Duration),
+    Samples(u64),
+    Explicit(Box<[u64]>), // Sample numbers, in any order
+}
+
+/// Picks the frame containing each target sample, `audio_offset` is the offset of the first frame
+pub fn build_seek_table(
+    frames: &[FrameIndexEntry],
+    audio_offset: u64,
+    sample_rate: u32,
+    spacing: &SeekSpacing,
+) -> MetadataBlockSeekTable {
+    let interval = match spacing {
+        SeekSpacing::Time(interval) => Some(
+            (interval.as_nanos() * sample_rate as u128 / 1_000_000_000).min(u64::MAX as u128)
+                as u64,
+        ),
+        SeekSpacing::Samples(interval) => Some(*interval),
+        SeekSpacing::Explicit(_) => None,
+    };
+    let mut explicit = match (spacing, interval) {
+        (SeekSpacing::Explicit(samples), _) => samples.to_vec(),
+        (_, Some(interval)) if interval > 0 => Vec::new(),
+        _ => vec![0],
+    };
+    explicit.sort_unstable();
+    let mut explicit = explicit.into_iter().peekable();
+
+    let mut seek_points: Vec<SeekPoint> = Vec::new();
+    for (idx, frame) in frames.iter().enumerate() {
+        if seek_points.len() == MAX_SEEK_POINTS {
+            break;
+        }
+        // A frame is picked for the targets from its first sample up to the start of the next
+        let frame_end = frames
+            .get(idx + 1)
+            .map_or(frame.first_sample + frame.block_size as u64, |next| {
+                next.first_sample
+            });
+        let has_target = match interval {
+            Some(interval) if interval > 0 => frame
+                .first_sample
+                .checked_add((interval - frame.first_sample % interval) % interval)
+                .is_some_and(|target| target < frame_end),
+            _ => {
+                let start = frame.first_sample;
+                while explicit.next_if(|target| *target < start).is_some() {}
+                explicit.peek().is_some_and(|target| *target < frame_end)
+            }
+        };
+        if !has_target
+            || seek_points.last().map(|point| point.sample_number) == Some(frame.first_sample)
+        {
+            continue;
+        }
+        seek_points.push(SeekPoint {
+            sample_number: frame.first_sample,
+            frame_offset: frame.offset - audio_offset,
+            num_samples: frame.block_size as u16,
+        });
+    }
+
+    MetadataBlockSeekTable {
+        seek_points: seek_points.into_boxed_slice(),
+    }
+}
+
+/// A metadata block kept as its serialized body, so blocks that cannot be parsed survive
+struct RawBlock {
+    block_type: u8,
+    body: Box<[u8]>,
+}
+
+/// Adds a SEEKTABLE to a native FLAC file, replacing any existing one. The audio is never
+/// re-encoded: the header is rewritten in place if padding makes room, otherwise the whole file
+/// is rewritten.
+pub fn write_seek_table(filename: &Path, spacing: &SeekSpacing) -> Result<(), Error> {
+    let mut reader = BufferedBitstreamReader::open(filename)?;
+    read_magic(&mut reader)?;
+    let mut blocks = Vec::new();
+    loop {
+        let is_last = reader.read_bit()?;
+        let block_type = reader.read_unsigned(7)? as u8;
+        let length = reader.read_unsigned(24)? as usize;
+        blocks.push(RawBlock {
+            block_type,
+            body: reader.read_bytes(length)?,
+        });
+        if is_last {
+            break;
+        }
+    }
+    let audio_offset = (reader.get_total_position() / 8) as u64;
+    drop(reader);
+
+    let mut index = FrameIndex::open(filename)?;
+    let sample_rate = index.stream_info().sample_rate;
+    let frames = (&mut index).collect::<Result<Vec<_>, Error>>()?;
+    let seek_table = build_seek_table(&frames, audio_offset, sample_rate, spacing);
+
+    // The new SEEKTABLE goes right after STREAMINFO and uses up the space of the old one
+    let old_size: usize = blocks.iter().map(|block| 4 + block.body.len()).sum();
+    let seek_table = serialize_block(MetadataBlockData::SeekTable(seek_table))?;
+    blocks.retain(|block| block.block_type != seek_table.block_type);
+    blocks.insert(1.min(blocks.len()), seek_table);
+
+    let new_size: usize = blocks.iter().map(|block| 4 + block.body.len()).sum();
+    let fits = fit_into(&mut blocks, old_size, new_size)?;
+
+    let header = serialize_header(&blocks)?;
+    if fits {
+        let mut file = OpenOptions::new().write(true).open(filename)?;
+        file.write_all(&header)?;
+        file.flush()?;
+        return Ok(());
+    }
+
+    // Write the new file next to the old one and replace it once complete
+    let mut temp_name = filename.as_os_str().to_owned();
+    temp_name.push(".muflac-tmp");
+    let temp_name = Path::new(&temp_name);
+    let result = (|| -> Result<(), Error> {
+        let mut input = File::open(filename)?;
+        input.seek(SeekFrom::Start(audio_offset))?;
+        let mut output = File::create(temp_name)?;
+        output.write_all(&header)?;
+        io::copy(&mut input, &mut output)?;
+        output.sync_all()?;
+        fs::set_permissions(temp_name, fs::metadata(filename)?.permissions())?;
+        Ok(())
+    })();
+    match result {
+        Ok(()) => Ok(fs::rename(temp_name, filename)?),
+        Err(err) => {
+            let _ = fs::remove_file(temp_name);
+            Err(err)
+        }
+    }
+}
+
+/// Resizes, adds or removes padding so the blocks take up exactly `old_size` bytes, returns false
+/// if they do not fit
+fn fit_into(blocks: &mut Vec<RawBlock>, old_size: usize, new_size: usize) -> Result<bool, Error> {
+    if new_size == old_size {
+        return Ok(true);
+    }
+
+    let padding_type = MetadataBlockData::Padding(0).block_type();
+    let padding_idx = blocks
+        .iter()
+        .rposition(|block| block.block_type == padding_type);
+    let other_size = match padding_idx {
+        Some(idx) => new_size - 4 - blocks[idx].body.len(),
+        None => new_size,
+    };
+
+    if other_size == old_size {
+        if let Some(idx) = padding_idx {
+            blocks.remove(idx);
+        }
+        Ok(true)
+    } else if other_size + 4 <= old_size {
+        let padding = serialize_block(MetadataBlockData::Padding(
+            (old_size - other_size - 4) as u32,
+        ))?;
+        match padding_idx {
+            Some(idx) => blocks[idx] = padding,
+            None => blocks.push(padding),
+        }
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+fn serialize_block(content: MetadataBlockData) -> Result<RawBlock, Error> {
+    let block_type = content.block_type();
+    let mut writer = BufferedBitstreamWriter::new(Vec::new());
+    write_metadata_block(
+        &mut writer,
+        &MetadataBlock {
+            is_last: false,
+            content,
+        },
+    )?;
+    let mut body = writer.finish()?;
+    body.drain(..4);
+    Ok(RawBlock {
+        block_type,
+        body: body.into_boxed_slice(),
+    })
+}
+
+fn serialize_header(blocks: &[RawBlock]) -> Result<Vec<u8>, Error> {
+    let mut writer = BufferedBitstreamWriter::new(Vec::new());
+    write_magic(&mut writer)?;
+    for (idx, block) in blocks.iter().enumerate() {
+        writer.write_bit(idx + 1 == blocks.len())?;
+        writer.write_unsigned(block.block_type as u128, 7)?;
+        writer.write_unsigned(block.body.len() as u128, 24)?;
+        writer.write_bytes(&block.body)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_types::ChannelAssignment;
+
+    /// Frames of `block_size` samples and 100 bytes each, starting at offset 1000
+    fn frames(num_frames: u64, block_size: u32) -> Vec<FrameIndexEntry> {
+        (0..num_frames)
+            .map(|idx| FrameIndexEntry {
+                offset: 1000 + 100 * idx,
+                size: 100,
+                first_sample: idx * block_size as u64,
+                block_size,
+                sample_rate: 44100,
+                channel_assignment: ChannelAssignment::Direct,
+                subframe_types: Box::new([]),
+            })
+            .collect()
+    }
+
+    fn sample_numbers(table: &MetadataBlockSeekTable) -> Vec<u64> {
+        table
+            .seek_points
+            .iter()
+            .map(|point| point.sample_number)
+            .collect()
+    }
+
+    #[test]
+    fn picks_frames_holding_targets() {
+        let frames = frames(10, 4096);
+        let table = build_seek_table(&frames, 1000, 44100, &SeekSpacing::Samples(10000));
+        assert_eq!(sample_numbers(&table), [0, 8192, 16384, 28672, 36864]);
+        assert_eq!(table.seek_points[1].frame_offset, 200);
+
+        let spacing = SeekSpacing::Explicit(Box::new([50000, 4096, 4095, 0, 1]));
+        let table = build_seek_table(&frames, 1000, 44100, &spacing);
+        assert_eq!(sample_numbers(&table), [0, 4096]);
+    }
+
+    #[test]
+    fn caps_seek_points() {
+        // One seek point per sample of a long stream
+        let frames = frames(1 << 20, 4096);
+        let table = build_seek_table(&frames, 1000, 44100, &SeekSpacing::Samples(1));
+        assert_eq!(table.seek_points.len(), MAX_SEEK_POINTS);
+    }
+}
@@ -0,0 +1,159 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use core::convert::TryInto;
+use serde::de::Error;
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// MD5 checksums as 32 lowercase hex digits
+pub mod hex_u128 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{:032x}", value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u128, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        u128::from_str_radix(&hex, 16).map_err(D::Error::custom)
+    }
+}
+
+/// Opaque binary data as standard base64
+pub mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<[u8]>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        STANDARD
+            .decode(encoded)
+            .map(Vec::into_boxed_slice)
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Text that is valid UTF-8 as a string, anything else as `{"base64": ...}` so no byte is lost
+struct Text<'a>(&'a [u8]);
+
+impl Serialize for Text<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match core::str::from_utf8(self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("base64", &STANDARD.encode(self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+/// Either form of `Text`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OwnedText {
+    Utf8(String),
+    Bytes { base64: String },
+}
+
+impl OwnedText {
+    fn into_bytes<E: Error>(self) -> Result<Vec<u8>, E> {
+        match self {
+            OwnedText::Utf8(text) => Ok(text.into_bytes()),
+            OwnedText::Bytes { base64 } => STANDARD.decode(base64).map_err(E::custom),
+        }
+    }
+}
+
+/// Fields the format defines as ASCII or UTF-8 text, as strings
+pub mod text_bytes {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        Text(value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Box<[u8]>, D::Error> {
+        Ok(OwnedText::deserialize(deserializer)?
+            .into_bytes()?
+            .into_boxed_slice())
+    }
+}
+
+/// Lists of text fields such as Vorbis comments, as arrays of strings
+pub mod text_bytes_list {
+    use super::*;
+    use serde::ser::SerializeSeq;
+
+    type TextList = Box<[Box<[u8]>]>;
+
+    pub fn serialize<S: Serializer>(value: &[Box<[u8]>], serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(value.len()))?;
+        for text in value {
+            seq.serialize_element(&Text(text))?;
+        }
+        seq.end()
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<TextList, D::Error> {
+        Vec::<OwnedText>::deserialize(deserializer)?
+            .into_iter()
+            .map(|text| Ok(text.into_bytes()?.into_boxed_slice()))
+            .collect()
+    }
+}
+
+/// Fixed length text fields such as the ISRC, as strings
+pub mod text_array {
+    use super::*;
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        value: &[u8; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Text(value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[u8; N], D::Error> {
+        let bytes = OwnedText::deserialize(deserializer)?.into_bytes()?;
+        let len = bytes.len();
+        bytes
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"a fixed length string"))
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use crate::metadata_types::MetadataBlockVorbisComment;
+
+    #[test]
+    fn text_round_trips_invalid_utf8() {
+        let comment = MetadataBlockVorbisComment {
+            vendor: Box::from(&b"muflac"[..]),
+            comments: Box::new([
+                Box::from(&b"TITLE=ok"[..]),
+                Box::from(&b"TITLE=\xff\xfe"[..]),
+            ]),
+        };
+        let json = serde_json::to_string(&comment).unwrap();
+        assert_eq!(
+            json,
+            r#"{"vendor":"muflac","comments":["TITLE=ok",{"base64":"VElUTEU9//4="}]}"#
+        );
+
+        let comment: MetadataBlockVorbisComment = serde_json::from_str(&json).unwrap();
+        assert_eq!(&comment.vendor[..], b"muflac");
+        assert_eq!(&comment.comments[1][..], b"TITLE=\xff\xfe");
+    }
+}
@@ -0,0 +1,588 @@
+#!/usr/bin/env python3
+"""Generates the conformance corpus in this directory.
+
+The streams are written by this small FLAC encoder, which shares no code with muflac, so the
+decoder is checked against an independent reading of the format. Everything is derived from a
+fixed seed per file, so running `python3 generate.py` (Python 3.11, standard library only)
+reproduces the files byte for byte.
+
+Every random choice is drawn from the per-file generator in a fixed order, so changing the order
+of the calls below changes the output.
+"""
+import hashlib
+import math
+import os
+import random
+import struct
+
+# Predictor coefficients of the FIXED subframe orders 0 to 4
+FIXED_COEFFICIENTS = [
+    [],
+    [1],
+    [2, -1],
+    [3, -3, 1],
+    [4, -6, 4, -1],
+]
+
+BLOCK_SIZE_CODES = {
+    192: 1,
+    576: 2,
+    1152: 3,
+    2304: 4,
+    4608: 5,
+    256: 8,
+    512: 9,
+    1024: 10,
+    2048: 11,
+    4096: 12,
+    8192: 13,
+    16384: 14,
+    32768: 15,
+}
+SAMPLE_RATE_CODES = {
+    88200: 1,
+    176400: 2,
+    192000: 3,
+    8000: 4,
+    16000: 5,
+    22050: 6,
+    24000: 7,
+    32000: 8,
+    44100: 9,
+    48000: 10,
+    96000: 11,
+}
+SAMPLE_DEPTH_CODES = {8: 1, 12: 2, 16: 4, 24: 5, 32: 6}
+STEREO_MODE_CODES = {"independent": 1, "left_side": 8, "right_side": 9, "mid_side": 10}
+
+
+class BitWriter:
+    """Collects bits most significant first into bytes."""
+
+    def __init__(self):
+        self.output = bytearray()
+        self.current_byte = 0
+        self.num_bits = 0
+
+    def write_unsigned(self, value, num_bits):
+        """Writes the low `num_bits` bits of `value`."""
+        for shift in range(num_bits - 1, -1, -1):
+            self.current_byte = (self.current_byte << 1) | ((value >> shift) & 1)
+            self.num_bits += 1
+            if self.num_bits == 8:
+                self.output.append(self.current_byte)
+                self.current_byte = 0
+                self.num_bits = 0
+
+    def write_signed(self, value, num_bits):
+        """Writes `value` in two's complement."""
+        self.write_unsigned(value & ((1 << num_bits) - 1), num_bits)
+
+    def write_unary(self, value):
+        """Writes `value` zero bits followed by a one bit."""
+        for _ in range(value):
+            self.write_unsigned(0, 1)
+        self.write_unsigned(1, 1)
+
+    def align(self):
+        """Pads with zero bits up to the next byte boundary."""
+        while self.num_bits:
+            self.write_unsigned(0, 1)
+
+    def bytes(self):
+        """Returns everything written so far, which has to end on a byte boundary."""
+        assert self.num_bits == 0
+        return bytes(self.output)
+
+
+def crc8(data):
+    """CRC-8 of frame headers, polynomial 0x07."""
+    crc = 0
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            if crc & 0x80:
+                crc = ((crc << 1) ^ 0x07) & 0xFF
+            else:
+                crc = (crc << 1) & 0xFF
+    return crc
+
+
+def crc16(data):
+    """CRC-16 of whole frames, polynomial 0x8005."""
+    crc = 0
+    for byte in data:
+        crc ^= byte << 8
+        for _ in range(8):
+            if crc & 0x8000:
+                crc = ((crc << 1) ^ 0x8005) & 0xFFFF
+            else:
+                crc = (crc << 1) & 0xFFFF
+    return crc
+
+
+def coded_number(number):
+    """Codes a frame or sample number like UTF-8, extended to 7 bytes."""
+    if number < 0x80:
+        return bytes([number])
+
+    # Each length holds 5 more bits than the one before
+    for length in range(2, 8):
+        if length == 7 or number < 1 << (5 * length + 1):
+            break
+
+    continuation = []
+    for _ in range(length - 1):
+        continuation.insert(0, 0x80 | (number & 0x3F))
+        number >>= 6
+    first = ((0xFF << (8 - length)) & 0xFF) | number
+    return bytes([first]) + bytes(continuation)
+
+
+def write_residual(writer, residual, order, partition_order, rng, escape_probability, rice2):
+    """Writes a partitioned Rice coded residual, escaping some partitions at random."""
+    block_size = len(residual) + order
+    if any(abs(value) >= 1 << 16 for value in residual):
+        rice2 = True
+    writer.write_unsigned(1 if rice2 else 0, 2)
+    writer.write_unsigned(partition_order, 4)
+
+    partition_size = block_size >> partition_order
+    parameter_bits = 5 if rice2 else 4
+    escape_code = (1 << parameter_bits) - 1
+    start = 0
+    for partition_idx in range(1 << partition_order):
+        # The warmup samples come out of the first partition
+        num_samples = partition_size
+        if partition_idx == 0:
+            num_samples -= order
+        partition = residual[start : start + num_samples]
+        start += num_samples
+
+        if rng.random() < escape_probability:
+            largest = max([abs(value) for value in partition] + [0])
+            if largest == 0:
+                escape_bits = 0
+            else:
+                escape_bits = max(int(largest).bit_length() + 1, 1)
+            escape_bits = min(escape_bits, 31)
+
+            if escape_bits > 0:
+                low = -(1 << (escape_bits - 1))
+                high = 1 << (escape_bits - 1)
+                fits = all(low <= value < high for value in partition)
+            else:
+                fits = all(value == 0 for value in partition)
+            if fits:
+                writer.write_unsigned(escape_code, parameter_bits)
+                writer.write_unsigned(escape_bits, 5)
+                for value in partition:
+                    if escape_bits:
+                        writer.write_signed(value, escape_bits)
+                continue
+
+        mean = sum(abs(value) for value in partition) / max(len(partition), 1)
+        if mean > 0:
+            parameter = max(0, int(math.log2(mean + 1)))
+        else:
+            parameter = 0
+        parameter = min(parameter, escape_code - 1)
+        writer.write_unsigned(parameter, parameter_bits)
+
+        for value in partition:
+            if value >= 0:
+                folded = value << 1
+            else:
+                folded = ((-value) << 1) - 1
+            writer.write_unary(folded >> parameter)
+            if parameter:
+                writer.write_unsigned(folded & ((1 << parameter) - 1), parameter)
+
+
+def fits_in_32_bits(values):
+    """Whether every value fits a signed 32-bit residual."""
+    return all(-(1 << 31) <= value < 1 << 31 for value in values)
+
+
+def write_subframe(writer, samples, sample_depth, kind, rng):
+    """Writes one channel of a frame as a subframe of the given kind, if the samples allow it."""
+    wasted_bits = 0
+    if rng.random() < 0.3 and any(samples):
+        while (
+            all(((sample >> wasted_bits) & 1) == 0 for sample in samples)
+            and wasted_bits < sample_depth - 1
+        ):
+            wasted_bits += 1
+    if kind == "const" and not all(sample == samples[0] for sample in samples):
+        kind = "verbatim"
+
+    shifted = [sample >> wasted_bits for sample in samples]
+    depth = sample_depth - wasted_bits
+    block_size = len(samples)
+
+    def write_header(subframe_type):
+        writer.write_unsigned(0, 1)
+        writer.write_unsigned(subframe_type, 6)
+        if wasted_bits:
+            writer.write_unsigned(1, 1)
+            writer.write_unary(wasted_bits - 1)
+        else:
+            writer.write_unsigned(0, 1)
+
+    def write_verbatim():
+        write_header(1)
+        for sample in shifted:
+            writer.write_signed(sample, depth)
+
+    if kind == "const":
+        write_header(0)
+        writer.write_signed(shifted[0], depth)
+        return
+    if kind == "verbatim":
+        write_verbatim()
+        return
+
+    # Partitions have to divide the block evenly and hold more than 32 samples
+    partition_order = rng.randint(0, 8)
+    while partition_order > 0 and (
+        block_size % (1 << partition_order) or block_size >> partition_order <= 32
+    ):
+        partition_order -= 1
+
+    if kind == "fixed":
+        order = rng.randint(0, min(4, block_size - 1))
+        if block_size >> partition_order < order:
+            partition_order = 0
+        coefficients = FIXED_COEFFICIENTS[order]
+        residual = [
+            shifted[i] - sum(coefficients[j] * shifted[i - 1 - j] for j in range(order))
+            for i in range(order, block_size)
+        ]
+        if not fits_in_32_bits(residual):
+            write_verbatim()
+            return
+
+        write_header(8 + order)
+        for sample in shifted[:order]:
+            writer.write_signed(sample, depth)
+        rice2 = rng.random() < 0.3
+        write_residual(writer, residual, order, partition_order, rng, 0.1, rice2)
+        return
+
+    order = rng.randint(1, min(32, block_size - 1))
+    if block_size >> partition_order < order:
+        partition_order = 0
+    precision = rng.randint(5, 15)
+    shift = rng.randint(0, min(15, precision))
+    limit = 1 << (precision - 1)
+    coefficients = [rng.randint(-limit, limit - 1) // (j + 2) for j in range(order)]
+
+    # Mostly a second order predictor like FIXED order 2, so the residual stays small
+    if order >= 2 and shift < precision - 3:
+        coefficients[0] = min(limit - 1, 2 << shift)
+        coefficients[1] = max(-limit, -(1 << shift))
+        for j in range(2, order):
+            if rng.random() < 0.7:
+                coefficients[j] = 0
+            else:
+                coefficients[j] = coefficients[j] // 8
+
+    residual = [
+        shifted[i]
+        - (sum(coefficients[j] * shifted[i - 1 - j] for j in range(order)) >> shift)
+        for i in range(order, block_size)
+    ]
+    if not fits_in_32_bits(residual):
+        write_verbatim()
+        return
+
+    write_header(32 + order - 1)
+    for sample in shifted[:order]:
+        writer.write_signed(sample, depth)
+    writer.write_unsigned(precision - 1, 4)
+    writer.write_signed(shift, 5)
+    for coefficient in coefficients:
+        writer.write_signed(coefficient, precision)
+    rice2 = rng.random() < 0.3
+    write_residual(writer, residual, order, partition_order, rng, 0.05, rice2)
+
+
+def write_frame_header(number, block_size, sample_rate, sample_depth, num_channels, variable, rng):
+    """Starts a frame, returns the writer and the stereo mode, which is None for other layouts.
+
+    Block sizes, sample rates and sample depths are sometimes coded explicitly or taken from
+    STREAMINFO even where a shorter code exists.
+    """
+    writer = BitWriter()
+    writer.write_unsigned(0x3FFE, 14)
+    writer.write_unsigned(0, 1)
+    writer.write_unsigned(1 if variable else 0, 1)
+
+    block_size_code = BLOCK_SIZE_CODES.get(block_size)
+    if block_size_code is None or rng.random() < 0.2:
+        block_size_code = 6 if block_size <= 256 else 7
+    writer.write_unsigned(block_size_code, 4)
+
+    sample_rate_code = SAMPLE_RATE_CODES.get(sample_rate, 0)
+    if (
+        sample_rate_code == 0
+        and sample_rate % 10 == 0
+        and sample_rate // 10 < 65536
+        and rng.random() < 0.5
+    ):
+        sample_rate_code = 14
+    elif sample_rate_code == 0 and sample_rate < 65536 and rng.random() < 0.5:
+        sample_rate_code = 13
+    writer.write_unsigned(sample_rate_code, 4)
+
+    stereo_mode = None
+    channel_code = num_channels - 1
+    if num_channels == 2:
+        stereo_mode = rng.choice(["independent", "left_side", "right_side", "mid_side"])
+        channel_code = STEREO_MODE_CODES[stereo_mode]
+    writer.write_unsigned(channel_code, 4)
+
+    if rng.random() < 0.8:
+        sample_depth_code = SAMPLE_DEPTH_CODES.get(sample_depth, 0)
+    else:
+        sample_depth_code = 0
+    writer.write_unsigned(sample_depth_code, 3)
+    writer.write_unsigned(0, 1)
+
+    for byte in coded_number(number):
+        writer.write_unsigned(byte, 8)
+    if block_size_code == 6:
+        writer.write_unsigned(block_size - 1, 8)
+    elif block_size_code == 7:
+        writer.write_unsigned(block_size - 1, 16)
+    if sample_rate_code == 13:
+        writer.write_unsigned(sample_rate, 16)
+    elif sample_rate_code == 14:
+        writer.write_unsigned(sample_rate // 10, 16)
+
+    writer.write_unsigned(crc8(writer.bytes()), 8)
+    return writer, stereo_mode
+
+
+def generate_samples(num_channels, sample_depth, num_samples, signal, rng):
+    """Returns one list of samples per channel."""
+    lowest = -(1 << (sample_depth - 1))
+    highest = (1 << (sample_depth - 1)) - 1
+    noise_scale = highest >> 12 or 1
+
+    channels = [[0] * num_samples for _ in range(num_channels)]
+    for channel_idx, channel in enumerate(channels):
+        phase = rng.random() * 6
+        for i in range(num_samples):
+            if signal == "noise":
+                # A sine wave at half scale with some noise on top
+                wave = math.sin(i * 0.01 * (channel_idx + 1) + phase) * highest * 0.5
+                noise = rng.randint(-100, 100) * noise_scale // 4
+                value = int(wave + noise)
+            elif signal == "extreme":
+                value = rng.choice([lowest, highest, lowest + 1, highest - 1, 0])
+            else:
+                value = 0
+            channel[i] = max(lowest, min(highest, value))
+    return channels
+
+
+def stream_md5(channels, sample_depth):
+    """MD5 of the interleaved samples as little endian integers of whole bytes."""
+    md5 = hashlib.md5()
+    num_bytes = (sample_depth + 7) // 8
+    mask = (1 << (8 * num_bytes)) - 1
+    for i in range(len(channels[0])):
+        for channel in channels:
+            md5.update((channel[i] & mask).to_bytes(num_bytes, "little"))
+    return md5.digest()
+
+
+def encode(
+    path,
+    chans,
+    bps,
+    rate,
+    total,
+    blocksizes=None,
+    variable=False,
+    seed=1,
+    signal="noise",
+    kinds=("const", "verbatim", "fixed", "lpc"),
+    extra_blocks=b"",
+):
+    """Writes a FLAC file of `total` samples per channel and returns the samples.
+
+    Block sizes cycle through `blocksizes`, every subframe picks one of `kinds` at random and
+    `extra_blocks` are serialized metadata blocks that follow STREAMINFO.
+    """
+    rng = random.Random(seed)
+    channels = generate_samples(chans, bps, total, signal, rng)
+
+    block_sizes = []
+    remaining = total
+    while remaining > 0:
+        if blocksizes:
+            block_size = blocksizes[len(block_sizes) % len(blocksizes)]
+        else:
+            block_size = 4096
+        block_size = min(block_size, remaining)
+        block_sizes.append(block_size)
+        remaining -= block_size
+
+    audio = bytearray()
+    min_frame_size = 1 << 30
+    max_frame_size = 0
+    first_sample = 0
+    for frame_idx, block_size in enumerate(block_sizes):
+        end = first_sample + block_size
+        block = [channel[first_sample:end] for channel in channels]
+        # Some blocks are made constant, which changes the samples of the stream as well
+        if rng.random() < 0.1:
+            for channel, samples in zip(channels, block):
+                samples[:] = [samples[0]] * block_size
+                channel[first_sample:end] = samples
+
+        number = first_sample if variable else frame_idx
+        writer, stereo_mode = write_frame_header(
+            number, block_size, rate, bps, chans, variable, rng
+        )
+
+        # Side channels take one more bit
+        if stereo_mode in ("left_side", "right_side", "mid_side"):
+            left, right = block
+            side = [l - r for l, r in zip(left, right)]
+            if stereo_mode == "left_side":
+                subframes = [(left, bps), (side, bps + 1)]
+            elif stereo_mode == "right_side":
+                subframes = [(side, bps + 1), (right, bps)]
+            else:
+                mid = [(l + r) >> 1 for l, r in zip(left, right)]
+                subframes = [(mid, bps), (side, bps + 1)]
+        else:
+            subframes = [(samples, bps) for samples in block]
+
+        for samples, sample_depth in subframes:
+            kind = rng.choice(kinds)
+            if block_size < 2 and kind in ("fixed", "lpc"):
+                kind = "verbatim"
+            if all(sample == samples[0] for sample in samples) and rng.random() < 0.8:
+                kind = "const"
+            write_subframe(writer, samples, sample_depth, kind, rng)
+
+        writer.align()
+        frame = writer.bytes()
+        frame += struct.pack(">H", crc16(frame))
+        min_frame_size = min(min_frame_size, len(frame))
+        max_frame_size = max(max_frame_size, len(frame))
+        audio += frame
+        first_sample = end
+
+    if variable:
+        min_block_size = min(block_sizes)
+        max_block_size = max(block_sizes)
+    else:
+        min_block_size = max_block_size = block_sizes[0]
+
+    stream_info = struct.pack(">HH", min(min_block_size, 65535), max_block_size)
+    stream_info += min_frame_size.to_bytes(3, "big")
+    stream_info += max_frame_size.to_bytes(3, "big")
+    packed = rate << 44 | (chans - 1) << 41 | (bps - 1) << 36 | total
+    stream_info += packed.to_bytes(8, "big")
+    stream_info += stream_md5(channels, bps)
+
+    # STREAMINFO is the last block unless others follow
+    is_last = 0x00 if extra_blocks else 0x80
+    header = b"fLaC" + bytes([is_last, 0, 0, 34]) + stream_info + extra_blocks
+    with open(path, "wb") as file:
+        file.write(header + audio)
+    return channels
+
+
+def padding_block(length, last=True):
+    """A serialized PADDING block of `length` zero bytes."""
+    header = (0x80 if last else 0) | 1
+    return bytes([header]) + length.to_bytes(3, "big") + b"\0" * length
+
+
+CASES = [
+    ("01-mono-8bit-8khz", dict(chans=1, bps=8, rate=8000, total=6000, blocksizes=[1152])),
+    ("02-stereo-16bit-44khz", dict(chans=2, bps=16, rate=44100, total=9000, blocksizes=[4096])),
+    ("03-3ch-12bit-22khz", dict(chans=3, bps=12, rate=22050, total=5000, blocksizes=[576])),
+    ("04-4ch-20bit-32khz", dict(chans=4, bps=20, rate=32000, total=4000, blocksizes=[1024])),
+    ("05-5ch-24bit-48khz", dict(chans=5, bps=24, rate=48000, total=3000, blocksizes=[2048])),
+    (
+        "06-6ch-8bit-96khz",
+        dict(
+            chans=6,
+            bps=8,
+            rate=96000,
+            total=3000,
+            blocksizes=[192],
+            extra_blocks=padding_block(100),
+        ),
+    ),
+    ("07-7ch-16bit-88khz", dict(chans=7, bps=16, rate=88200, total=2500, blocksizes=[512])),
+    ("08-8ch-24bit-192khz", dict(chans=8, bps=24, rate=192000, total=2500, blocksizes=[1000])),
+    ("09-mono-4bit", dict(chans=1, bps=4, rate=16000, total=4000, blocksizes=[256])),
+    ("10-stereo-32bit", dict(chans=2, bps=32, rate=48000, total=6000, blocksizes=[2048])),
+    (
+        "11-variable-blocksize",
+        dict(
+            chans=2,
+            bps=16,
+            rate=44100,
+            total=9000,
+            blocksizes=[1000, 577, 2304, 17, 4608],
+            variable=True,
+        ),
+    ),
+    ("12-odd-sample-rate-11025", dict(chans=2, bps=16, rate=11025, total=5000, blocksizes=[1024])),
+    ("13-odd-sample-rate-37", dict(chans=1, bps=16, rate=37, total=2000, blocksizes=[300])),
+    (
+        "14-odd-sample-rate-655350",
+        dict(chans=1, bps=16, rate=655350, total=3000, blocksizes=[4096]),
+    ),
+    ("15-tiny-blocks", dict(chans=2, bps=16, rate=44100, total=700, blocksizes=[1, 2, 16, 33])),
+    (
+        "16-verbatim",
+        dict(chans=2, bps=24, rate=48000, total=3000, blocksizes=[1152], kinds=("verbatim",)),
+    ),
+    (
+        "17-constant",
+        dict(
+            chans=2,
+            bps=16,
+            rate=44100,
+            total=9000,
+            blocksizes=[4096],
+            signal="zero",
+            kinds=("const",),
+        ),
+    ),
+    (
+        "18-fixed",
+        dict(chans=2, bps=16, rate=44100, total=9000, blocksizes=[4096], kinds=("fixed",)),
+    ),
+    ("19-lpc", dict(chans=2, bps=24, rate=96000, total=9000, blocksizes=[4096], kinds=("lpc",))),
+    (
+        "20-extreme-values-24bit",
+        dict(chans=2, bps=24, rate=48000, total=4000, blocksizes=[1152], signal="extreme"),
+    ),
+    (
+        "21-extreme-values-32bit",
+        dict(chans=2, bps=32, rate=48000, total=4000, blocksizes=[1152], signal="extreme"),
+    ),
+    ("22-large-blocks", dict(chans=1, bps=16, rate=44100, total=40000, blocksizes=[32768])),
+]
+
+
+def main():
+    """Writes every case next to this script."""
+    out_dir = os.path.dirname(os.path.abspath(__file__))
+    for idx, (name, options) in enumerate(CASES):
+        encode(os.path.join(out_dir, name + ".flac"), seed=100 + idx, **options)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,374 @@
+//! Decodes every FLAC file of a corpus and checks the MD5 of the samples against STREAMINFO.
+//! `test_flac/conformance` holds synthetic streams covering every subframe type, Rice2 and
+//! escaped partitions, wasted bits, 1 to 8 channels, 4 to 32 bits, variable block sizes and odd
+//! sample rates, written by an encoder independent of this crate (`generate.py` in the same
+//! directory). More files, such as the `subset` directory of flac-test-files, can be added with
+//! `MUFLAC_TEST_FILES=<dir>`.
+
+use md5::{Digest, Md5};
+use muflac::bitstream::BufferedBitstreamReader;
+use muflac::decoder::{DecodedFrame, Decoder, Recovery};
+use muflac::frame_index::FrameIndex;
+use muflac::parallel_decoder::ParallelDecoder;
+use muflac::position::Continuity;
+use muflac::push_decoder::{PushDecoder, PushEvent};
+use muflac::seek_table::{build_seek_table, SeekSpacing};
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+fn corpus() -> Vec<PathBuf> {
+    let mut dirs = vec![Path::new(env!("CARGO_MANIFEST_DIR")).join("test_flac")];
+    if let Some(dir) = env::var_os("MUFLAC_TEST_FILES") {
+        dirs.push(dir.into());
+    }
+
+    let mut files = Vec::new();
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.extension().is_some_and(|e| e == "flac") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Decodes a whole file, returning an error message for anything not matching STREAMINFO
+fn check(path: &Path) -> Result<(), String> {
+    let mut decoder = Decoder::open(path).map_err(|e| e.to_string())?;
+    let stream_info = decoder.stream_info().clone();
+    let bytes_per_sample = (stream_info.sample_depth as usize).div_ceil(8);
+
+    let mut md5 = Md5::new();
+    let mut num_samples = 0;
+    let mut buf = Vec::new();
+    while let Some(frame) = decoder.next_frame().map_err(|e| e.to_string())? {
+        buf.clear();
+        for i in 0..frame.frame.block_size as usize {
+            for channel in frame.samples.iter() {
+                buf.extend_from_slice(&channel[i].to_le_bytes()[..bytes_per_sample]);
+            }
+        }
+        md5.update(&buf);
+        num_samples += frame.frame.block_size as u64;
+    }
+
+    if stream_info.num_samples != 0 && num_samples != stream_info.num_samples {
+        return Err(format!(
+            "{} samples instead of {}",
+            num_samples, stream_info.num_samples
+        ));
+    }
+    let md5 = u128::from_be_bytes(md5.finalize().into());
+    // An MD5 of 0 means the encoder did not compute one
+    if stream_info.decoded_checksum != 0 && md5 != stream_info.decoded_checksum {
+        return Err(format!(
+            "MD5 {:032x} instead of {:032x}",
+            md5, stream_info.decoded_checksum
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn decodes_corpus() {
+    let mut num_checked = 0;
+    let mut failures = Vec::new();
+    for path in corpus() {
+        // Files that are still Git LFS pointers have not been downloaded
+        if !fs::read(&path).unwrap().starts_with(b"fLaC") {
+            eprintln!("skipping {}, not a FLAC file", path.display());
+            continue;
+        }
+        num_checked += 1;
+        if let Err(e) = check(&path) {
+            failures.push(format!("{}: {}", path.display(), e));
+        }
+    }
+
+    assert!(num_checked > 0, "no FLAC files found");
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+/// Reads a file of the synthetic corpus, or returns None if it is still a Git LFS pointer
+fn read_conformance_file(name: &str) -> Option<Vec<u8>> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test_flac/conformance")
+        .join(name);
+    let data = fs::read(&path).unwrap();
+    if !data.starts_with(b"fLaC") {
+        eprintln!("skipping {}, not a FLAC file", path.display());
+        return None;
+    }
+    Some(data)
+}
+
+/// CRC-8 of frame headers, polynomial 0x07
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn decode_all(data: &[u8], recovery: Recovery) -> Vec<DecodedFrame> {
+    let mut decoder = Decoder::new(BufferedBitstreamReader::new(data)).unwrap();
+    decoder.set_recovery(recovery);
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.next_frame().unwrap() {
+        frames.push(frame);
+    }
+    frames
+}
+
+/// Feeds a file to a `PushDecoder` in chunks of `chunk_size` bytes, collecting its frames
+fn push_decode_all(data: &[u8], chunk_size: usize) -> Vec<DecodedFrame> {
+    let mut decoder = PushDecoder::new();
+    let mut chunks = data.chunks(chunk_size);
+    let mut frames = Vec::new();
+    loop {
+        match decoder.next_event().unwrap() {
+            PushEvent::Metadata(_) => {}
+            PushEvent::Frame(frame) => frames.push(frame),
+            PushEvent::NeedMoreData => match chunks.next() {
+                Some(chunk) => decoder.feed(chunk),
+                None => decoder.finish(),
+            },
+            PushEvent::End => return frames,
+        }
+    }
+}
+
+#[test]
+fn push_decoder_matches_decoder() {
+    for path in corpus() {
+        let data = fs::read(&path).unwrap();
+        if !data.starts_with(b"fLaC") {
+            continue;
+        }
+        let expected = decode_all(&data, Recovery::Off);
+        for chunk_size in [1, 1000, 65536] {
+            let frames = push_decode_all(&data, chunk_size);
+            assert_eq!(
+                frames.len(),
+                expected.len(),
+                "{} in chunks of {}",
+                path.display(),
+                chunk_size
+            );
+            for (frame, expected) in frames.iter().zip(&expected) {
+                assert_eq!(frame.samples, expected.samples, "{}", path.display());
+                assert_eq!(frame.position.first_sample, expected.position.first_sample);
+            }
+        }
+    }
+}
+
+#[test]
+fn parallel_decoder_matches_decoder() {
+    for path in corpus() {
+        let data = fs::read(&path).unwrap();
+        if !data.starts_with(b"fLaC") {
+            continue;
+        }
+        let expected = decode_all(&data, Recovery::Off);
+        for num_threads in [1, 3] {
+            let mut decoder = ParallelDecoder::new(Cursor::new(data.clone()), num_threads).unwrap();
+            let mut frames = Vec::new();
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+            assert_eq!(
+                frames.len(),
+                expected.len(),
+                "{} on {} threads",
+                path.display(),
+                num_threads
+            );
+            for (frame, expected) in frames.iter().zip(&expected) {
+                assert_eq!(frame.samples, expected.samples, "{}", path.display());
+                assert_eq!(frame.position.first_sample, expected.position.first_sample);
+            }
+        }
+    }
+}
+
+#[test]
+fn recovers_after_damaged_frame() {
+    let data = match read_conformance_file("01-mono-8bit-8khz.flac") {
+        Some(data) => data,
+        None => return,
+    };
+    let mut index = FrameIndex::new(BufferedBitstreamReader::new(&data[..])).unwrap();
+    let num_samples = index.stream_info().num_samples;
+    let mut entries = Vec::new();
+    while let Some(entry) = index.next_entry().unwrap() {
+        entries.push(entry);
+    }
+
+    // Claim 4608 instead of 1152 samples in the header of the third frame, keeping its CRC-8
+    // valid, so parsing it runs into the frames after it
+    let damaged = &entries[2];
+    let mut corrupted = data.clone();
+    let header = &mut corrupted[damaged.offset as usize..][..6];
+    assert_eq!(header[2] >> 4, 0b0011);
+    assert_eq!(crc8(&header[..5]), header[5]);
+    header[2] = 0b0101 << 4 | header[2] & 0x0F;
+    header[5] = crc8(&header[..5]);
+
+    let frames = decode_all(&corrupted, Recovery::Skip);
+    assert_eq!(frames.len(), entries.len() - 1);
+    let lost = frames[2].lost.as_ref().expect("no lost samples reported");
+    assert_eq!(lost.first_sample, damaged.first_sample);
+    assert_eq!(lost.num_samples, damaged.block_size as u64);
+    assert_eq!(frames[2].position.first_sample, entries[3].first_sample);
+    assert_eq!(
+        frames.iter().filter(|frame| frame.lost.is_some()).count(),
+        1
+    );
+
+    let frames = decode_all(&corrupted, Recovery::Silence);
+    assert_eq!(frames.len(), entries.len());
+    let total: u64 = frames
+        .iter()
+        .map(|frame| frame.frame.block_size as u64)
+        .sum();
+    assert_eq!(total, num_samples);
+    let silence = &frames[2];
+    assert_eq!(silence.frame.block_size, damaged.block_size);
+    assert_eq!(silence.position.first_sample, damaged.first_sample);
+    assert!(silence.samples.iter().flatten().all(|&sample| sample == 0));
+    assert!(silence.lost.is_some());
+    assert!(frames[3].lost.is_none());
+}
+
+#[test]
+fn tracks_variable_block_sizes() {
+    let data = match read_conformance_file("11-variable-blocksize.flac") {
+        Some(data) => data,
+        None => return,
+    };
+    // The encoder cycles through these block sizes, cutting the last frame short
+    let mut block_sizes = Vec::new();
+    let mut remaining = 9000;
+    for &block_size in [1000, 577, 2304, 17, 4608].iter().cycle() {
+        if remaining == 0 {
+            break;
+        }
+        block_sizes.push(block_size.min(remaining));
+        remaining -= block_sizes.last().unwrap();
+    }
+
+    let mut index = FrameIndex::new(BufferedBitstreamReader::new(&data[..])).unwrap();
+    let stream_info = index.stream_info().clone();
+    assert_eq!(stream_info.min_block_size, 17);
+    assert_eq!(stream_info.max_block_size, 4608);
+    let mut entries = Vec::new();
+    while let Some(entry) = index.next_entry().unwrap() {
+        entries.push(entry);
+    }
+    assert_eq!(entries.len(), block_sizes.len());
+    let mut first_sample = 0;
+    for (entry, &block_size) in entries.iter().zip(&block_sizes) {
+        assert_eq!(entry.first_sample, first_sample);
+        assert_eq!(entry.block_size, block_size);
+        first_sample += block_size as u64;
+    }
+    for pair in entries.windows(2) {
+        assert_eq!(pair[0].offset + pair[0].size as u64, pair[1].offset);
+    }
+    let last = entries.last().unwrap();
+    assert_eq!(last.offset + last.size as u64, data.len() as u64);
+
+    let frames = decode_all(&data, Recovery::Off);
+    assert_eq!(frames.len(), entries.len());
+    for (frame, entry) in frames.iter().zip(&entries) {
+        assert!(frame.frame.is_variable);
+        assert_eq!(frame.position.first_sample, entry.first_sample);
+        assert_eq!(frame.position.continuity, Continuity::Contiguous);
+    }
+
+    // Seek points land on the start of the frame holding each target sample
+    let audio_offset = entries[0].offset;
+    let seek_table = build_seek_table(
+        &entries,
+        audio_offset,
+        stream_info.sample_rate,
+        &SeekSpacing::Samples(2000),
+    );
+    let seek_points = &seek_table.seek_points;
+    assert_eq!(
+        seek_points
+            .iter()
+            .map(|point| point.sample_number)
+            .collect::<Vec<_>>(),
+        [0, 1577, 3898]
+    );
+    for point in seek_points.iter() {
+        let entry = entries
+            .iter()
+            .find(|entry| entry.first_sample == point.sample_number)
+            .unwrap();
+        assert_eq!(point.frame_offset, entry.offset - audio_offset);
+        assert_eq!(point.num_samples as u32, entry.block_size);
+    }
+}
+
+#[test]
+fn push_decoder_emits_frames_as_they_complete() {
+    let data = match read_conformance_file("02-stereo-16bit-44khz.flac") {
+        Some(data) => data,
+        None => return,
+    };
+    let expected = decode_all(&data, Recovery::Off);
+    let largest_frame = {
+        let mut index = FrameIndex::new(BufferedBitstreamReader::new(&data[..])).unwrap();
+        let mut largest = 0;
+        while let Some(entry) = index.next_entry().unwrap() {
+            largest = largest.max(entry.size as usize);
+        }
+        largest
+    };
+
+    // STREAMINFO frame sizes of 0 mean unknown, the frames must still end where they end
+    let mut unknown_sizes = data.clone();
+    unknown_sizes[12..18].fill(0);
+    for data in [data, unknown_sizes] {
+        let mut decoder = PushDecoder::new();
+        let mut num_frames = 0;
+        let mut max_buffered = 0;
+        for chunk in data.chunks(64) {
+            decoder.feed(chunk);
+            max_buffered = max_buffered.max(decoder.buffered());
+            loop {
+                match decoder.next_event().unwrap() {
+                    PushEvent::Frame(frame) => {
+                        assert_eq!(frame.samples, expected[num_frames].samples);
+                        num_frames += 1;
+                    }
+                    PushEvent::Metadata(_) => {}
+                    PushEvent::NeedMoreData => break,
+                    PushEvent::End => unreachable!(),
+                }
+            }
+        }
+        assert_eq!(num_frames, expected.len());
+        assert!(max_buffered < largest_frame + 64);
+        decoder.finish();
+        assert!(matches!(decoder.next_event().unwrap(), PushEvent::End));
+    }
+}
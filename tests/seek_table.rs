@@ -0,0 +1,114 @@
+//! Writes seek tables into copies of files from `test_flac/conformance` and checks that the
+//! audio is left untouched
+
+use muflac::decoder::Decoder;
+use muflac::frame_index::FrameIndex;
+use muflac::metadata_types::MetadataBlockData;
+use muflac::seek_table::{write_seek_table, SeekSpacing};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Copies a file of the synthetic corpus to a fresh temporary path, or returns None if it is
+/// still a Git LFS pointer
+fn copy_conformance_file(name: &str, test: &str) -> Option<PathBuf> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("test_flac/conformance")
+        .join(name);
+    let data = fs::read(&path).unwrap();
+    if !data.starts_with(b"fLaC") {
+        eprintln!("skipping {}, not a FLAC file", path.display());
+        return None;
+    }
+
+    let copy = env::temp_dir().join(format!("muflac-{}-{}.flac", test, std::process::id()));
+    fs::write(&copy, data).unwrap();
+    Some(copy)
+}
+
+/// Offset of the first frame in bytes
+fn audio_offset(path: &Path) -> usize {
+    let mut index = FrameIndex::open(path).unwrap();
+    index.next_entry().unwrap().unwrap().offset as usize
+}
+
+fn block_types(path: &Path) -> Vec<u8> {
+    let decoder = Decoder::open(path).unwrap();
+    decoder
+        .metadata()
+        .iter()
+        .map(|block| block.content.block_type())
+        .collect()
+}
+
+fn seek_points(path: &Path) -> usize {
+    let decoder = Decoder::open(path).unwrap();
+    decoder
+        .metadata()
+        .iter()
+        .find_map(|block| match &block.content {
+            MetadataBlockData::SeekTable(table) => Some(table.seek_points.len()),
+            _ => None,
+        })
+        .expect("no SEEKTABLE")
+}
+
+/// Writes a seek table, returning the audio bytes before and after
+fn write_and_compare(path: &Path, spacing: SeekSpacing) -> (Vec<u8>, Vec<u8>) {
+    let before = fs::read(path).unwrap();
+    let before = before[audio_offset(path)..].to_vec();
+    write_seek_table(path, &spacing).unwrap();
+    let after = fs::read(path).unwrap();
+    let after = after[audio_offset(path)..].to_vec();
+    (before, after)
+}
+
+#[test]
+fn uses_padding_in_place() {
+    // This file has 100 bytes of padding after STREAMINFO
+    let path = match copy_conformance_file("06-6ch-8bit-96khz.flac", "in-place") {
+        Some(path) => path,
+        None => return,
+    };
+    let length = fs::metadata(&path).unwrap().len();
+    let offset = audio_offset(&path);
+    assert_eq!(block_types(&path), [0, 1]);
+
+    let (before, after) = write_and_compare(&path, SeekSpacing::Samples(1000));
+    assert_eq!(fs::metadata(&path).unwrap().len(), length);
+    assert_eq!(audio_offset(&path), offset);
+    assert_eq!(before, after);
+    assert_eq!(block_types(&path), [0, 3, 1]);
+    assert_eq!(seek_points(&path), 3);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn grows_header_by_rewriting() {
+    let path = match copy_conformance_file("06-6ch-8bit-96khz.flac", "rewrite") {
+        Some(path) => path,
+        None => return,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+    }
+    let length = fs::metadata(&path).unwrap().len();
+
+    // 3000 samples in blocks of 192 give 16 seek points, 292 bytes in all, more than the padding
+    let (before, after) = write_and_compare(&path, SeekSpacing::Samples(192));
+    assert_eq!(before, after);
+    assert_eq!(block_types(&path), [0, 3, 1]);
+    assert_eq!(seek_points(&path), 16);
+    assert_eq!(fs::metadata(&path).unwrap().len(), length + 4 + 16 * 18);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    fs::remove_file(&path).unwrap();
+}